@@ -0,0 +1,386 @@
+//! This module implements constant-time portable AES, which encrypts 8 blocks at once in bitsliced form. It is `Backend::Bitsliced`, which is used instead of `sisd` when the `constant-time` feature is enabled and AES-NI is not available
+//!
+//! The 8 blocks are transposed into 8 128-bit "planes", plane `b` holding bit `b` of every byte of every block - Bit `8p + k` of a plane belongs to byte `p` (in FIPS-197 order) of block `k`
+//! Each byte position therefore occupies an 8-bit lane of every plane, so SubBytes becomes a boolean circuit over the planes (the Boyar-Peralta S-box), and ShiftRows and MixColumns become fixed lane permutations
+//! Nothing is ever indexed or branched on by secret data, so unlike `sisd` this leaks nothing through cache or branch timing
+
+use std::array;
+
+use super::{sisd, zeroize::Zeroizing};
+
+/// The number of blocks encrypted at once
+pub const BLOCKS: usize = 8;
+
+/// The lane each lane of the state is taken from by ShiftRows - Row `r` is rotated left by `r` places
+const SHIFT_ROWS: [usize; 16] = [0, 5, 10, 15, 4, 9, 14, 3, 8, 13, 2, 7, 12, 1, 6, 11];
+/// The lane each lane of the state is taken from by InvShiftRows - Row `r` is rotated right by `r` places
+const INV_SHIFT_ROWS: [usize; 16] = [0, 13, 10, 7, 4, 1, 14, 11, 8, 5, 2, 15, 12, 9, 6, 3];
+
+/// Bitsliced state of 8 blocks - Element `b` is the plane holding bit `b` of each byte
+type Planes = [u128; 8];
+
+/// Expands one 128-bit key into 11 128-bit round keys (AES-128)
+pub fn key_expansion(key: u128) -> [u128; 11] {
+	sisd::expand_words(sisd::words_128(key), sub_word)
+}
+
+/// Expands one 192-bit key, given as 3 64-bit words with the most significant first, into 13 128-bit round keys (AES-192)
+pub fn key_expansion_192(key: [u64; 3]) -> [u128; 13] {
+	sisd::expand_words(sisd::words_192(key), sub_word)
+}
+
+/// Expands one 256-bit key, given as 2 128-bit words with the most significant first, into 15 128-bit round keys (AES-256)
+pub fn key_expansion_256(key: [u128; 2]) -> [u128; 15] {
+	sisd::expand_words(sisd::words_256(key), sub_word)
+}
+
+fn sub_word(w: u32) -> u32 {
+	let mut q = bitslice(&[w as u128, 0, 0, 0, 0, 0, 0, 0]);
+	sub_bytes(&mut q);
+	unbitslice(&q)[0] as u32
+}
+
+/// Performs the cipher on a 128-bit state with 11, 13 or 15 128-bit round keys (AES-128, AES-192 or AES-256 respectively)
+///
+/// This costs as much as encrypting 8 blocks, so prefer `cipher_blocks` where possible
+pub fn cipher(state: u128, round_keys: &[u128]) -> u128 {
+	let mut states = [state];
+	cipher_blocks(&mut states, round_keys);
+	states[0]
+}
+
+/// Performs the cipher on each 128-bit state in `states` in-place with 11, 13 or 15 128-bit round keys, `BLOCKS` at a time
+pub fn cipher_blocks(states: &mut [u128], round_keys: &[u128]) {
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
+	let rks = bitslice_round_keys(round_keys);
+
+	for chunk in states.chunks_mut(BLOCKS) {
+		let mut q = load(chunk);
+
+		add_round_key(&mut q, &rks[0]);
+		for rk in &rks[1..nr] {
+			sub_bytes(&mut q);
+			shift_rows(&mut q, &SHIFT_ROWS);
+			mix_columns(&mut q);
+			add_round_key(&mut q, rk);
+		}
+		sub_bytes(&mut q);
+		shift_rows(&mut q, &SHIFT_ROWS);
+		add_round_key(&mut q, &rks[nr]);
+
+		store(&q, chunk);
+	}
+}
+
+/// Transforms the round keys produced by `key_expansion` into the decryption round keys used by `inv_cipher` (the key schedule of the FIPS-197 equivalent inverse cipher) - The round keys are reversed, and InvMixColumns is applied to all but the first and last
+pub fn inv_key_expansion(round_keys: &[u128]) -> Vec<u128> {
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
+
+	let inv_mix = |rk: u128| {
+		let mut q = bitslice(&[rk, 0, 0, 0, 0, 0, 0, 0]);
+		inv_mix_columns(&mut q);
+		unbitslice(&q)[0]
+	};
+
+	let mut dec_rks = Vec::with_capacity(round_keys.len());
+	dec_rks.push(round_keys[nr]);
+	dec_rks.extend(round_keys[1..nr].iter().rev().map(|rk| inv_mix(*rk)));
+	dec_rks.push(round_keys[0]);
+
+	dec_rks
+}
+
+/// Performs the inverse cipher on a 128-bit state with 11, 13 or 15 128-bit decryption round keys produced by `inv_key_expansion`
+///
+/// This costs as much as decrypting 8 blocks, so prefer `inv_cipher_blocks` where possible
+pub fn inv_cipher(state: u128, dec_round_keys: &[u128]) -> u128 {
+	let mut states = [state];
+	inv_cipher_blocks(&mut states, dec_round_keys);
+	states[0]
+}
+
+/// Performs the inverse cipher on each 128-bit state in `states` in-place with 11, 13 or 15 128-bit decryption round keys produced by `inv_key_expansion`, `BLOCKS` at a time
+///
+/// This is the FIPS-197 equivalent inverse cipher, which has the same sequence of steps as the cipher with each replaced by its inverse
+pub fn inv_cipher_blocks(states: &mut [u128], dec_round_keys: &[u128]) {
+	assert!(matches!(dec_round_keys.len(), 11 | 13 | 15));
+
+	let nr = dec_round_keys.len() - 1;
+	let rks = bitslice_round_keys(dec_round_keys);
+
+	for chunk in states.chunks_mut(BLOCKS) {
+		let mut q = load(chunk);
+
+		add_round_key(&mut q, &rks[0]);
+		for rk in &rks[1..nr] {
+			inv_sub_bytes(&mut q);
+			shift_rows(&mut q, &INV_SHIFT_ROWS);
+			inv_mix_columns(&mut q);
+			add_round_key(&mut q, rk);
+		}
+		inv_sub_bytes(&mut q);
+		shift_rows(&mut q, &INV_SHIFT_ROWS);
+		add_round_key(&mut q, &rks[nr]);
+
+		store(&q, chunk);
+	}
+}
+
+/// Bitslices up to `BLOCKS` states, padding with zero blocks
+fn load(states: &[u128]) -> Planes {
+	let mut blocks = [0u128; BLOCKS];
+	blocks[..states.len()].copy_from_slice(states);
+	bitslice(&blocks)
+}
+
+/// Writes the first `states.len()` blocks of `q` back to `states`
+fn store(q: &Planes, states: &mut [u128]) {
+	let len = states.len();
+	states.copy_from_slice(&unbitslice(q)[..len]);
+}
+
+/// Bitslices each round key, so that it applies to every block - The bitsliced round keys are zeroed when they are dropped
+fn bitslice_round_keys(round_keys: &[u128]) -> Zeroizing<Planes> {
+	Zeroizing::from_vec(round_keys.iter().map(|rk| bitslice(&[*rk; BLOCKS])).collect())
+}
+
+/// Transposes an 8x8 bit matrix, where row `i` is byte `i` of `x` and column `j` is bit `j` of each byte
+fn transpose_8x8(mut x: u64) -> u64 {
+	x = (x & 0xaa55aa55aa55aa55) | ((x & 0x00aa00aa00aa00aa) << 7) | ((x >> 7) & 0x00aa00aa00aa00aa);
+	x = (x & 0xcccc3333cccc3333) | ((x & 0x0000cccc0000cccc) << 14) | ((x >> 14) & 0x0000cccc0000cccc);
+	x = (x & 0xf0f0f0f00f0f0f0f) | ((x & 0x00000000f0f0f0f0) << 28) | ((x >> 28) & 0x00000000f0f0f0f0);
+	x
+}
+
+/// Converts 8 states into bitsliced form
+fn bitslice(blocks: &[u128; BLOCKS]) -> Planes {
+	let bytes = blocks.map(u128::to_be_bytes);
+	let mut q = [0u128; 8];
+
+	for p in 0..16 {
+		// Byte k of the matrix is byte p of block k, so after the transpose byte b holds bit b of byte p of each block
+		let lane = transpose_8x8(u64::from_le_bytes(bytes.map(|block| block[p])));
+		for (b, plane) in q.iter_mut().enumerate() {
+			*plane |= ((lane >> (8 * b)) as u8 as u128) << (8 * p);
+		}
+	}
+
+	q
+}
+
+/// Converts bitsliced form back into 8 states
+fn unbitslice(q: &Planes) -> [u128; BLOCKS] {
+	let mut bytes = [[0u8; 16]; BLOCKS];
+
+	for p in 0..16 {
+		let lane = transpose_8x8(u64::from_le_bytes(array::from_fn(|b| (q[b] >> (8 * p)) as u8)));
+		for (k, block) in bytes.iter_mut().enumerate() {
+			block[p] = (lane >> (8 * k)) as u8;
+		}
+	}
+
+	bytes.map(u128::from_be_bytes)
+}
+
+fn add_round_key(q: &mut Planes, rk: &Planes) {
+	for (plane, rk_plane) in q.iter_mut().zip(rk) {
+		*plane ^= rk_plane;
+	}
+}
+
+/// Applies the AES S-box to every byte, using the circuit of Boyar and Peralta (113 gates)
+fn sub_bytes(q: &mut Planes) {
+	// x0 is the most significant bit
+	let x0 = q[7];
+	let x1 = q[6];
+	let x2 = q[5];
+	let x3 = q[4];
+	let x4 = q[3];
+	let x5 = q[2];
+	let x6 = q[1];
+	let x7 = q[0];
+
+	// Top linear transformation
+	let y14 = x3 ^ x5;
+	let y13 = x0 ^ x6;
+	let y9 = x0 ^ x3;
+	let y8 = x0 ^ x5;
+	let t0 = x1 ^ x2;
+	let y1 = t0 ^ x7;
+	let y4 = y1 ^ x3;
+	let y12 = y13 ^ y14;
+	let y2 = y1 ^ x0;
+	let y5 = y1 ^ x6;
+	let y3 = y5 ^ y8;
+	let t1 = x4 ^ y12;
+	let y15 = t1 ^ x5;
+	let y20 = t1 ^ x1;
+	let y6 = y15 ^ x7;
+	let y10 = y15 ^ t0;
+	let y11 = y20 ^ y9;
+	let y7 = x7 ^ y11;
+	let y17 = y10 ^ y11;
+	let y19 = y10 ^ y8;
+	let y16 = t0 ^ y11;
+	let y21 = y13 ^ y16;
+	let y18 = x0 ^ y16;
+
+	// Non-linear section
+	let t2 = y12 & y15;
+	let t3 = y3 & y6;
+	let t4 = t3 ^ t2;
+	let t5 = y4 & x7;
+	let t6 = t5 ^ t2;
+	let t7 = y13 & y16;
+	let t8 = y5 & y1;
+	let t9 = t8 ^ t7;
+	let t10 = y2 & y7;
+	let t11 = t10 ^ t7;
+	let t12 = y9 & y11;
+	let t13 = y14 & y17;
+	let t14 = t13 ^ t12;
+	let t15 = y8 & y10;
+	let t16 = t15 ^ t12;
+	let t17 = t4 ^ t14;
+	let t18 = t6 ^ t16;
+	let t19 = t9 ^ t14;
+	let t20 = t11 ^ t16;
+	let t21 = t17 ^ y20;
+	let t22 = t18 ^ y19;
+	let t23 = t19 ^ y21;
+	let t24 = t20 ^ y18;
+
+	let t25 = t21 ^ t22;
+	let t26 = t21 & t23;
+	let t27 = t24 ^ t26;
+	let t28 = t25 & t27;
+	let t29 = t28 ^ t22;
+	let t30 = t23 ^ t24;
+	let t31 = t22 ^ t26;
+	let t32 = t31 & t30;
+	let t33 = t32 ^ t24;
+	let t34 = t23 ^ t33;
+	let t35 = t27 ^ t33;
+	let t36 = t24 & t35;
+	let t37 = t36 ^ t34;
+	let t38 = t27 ^ t36;
+	let t39 = t29 & t38;
+	let t40 = t25 ^ t39;
+
+	let t41 = t40 ^ t37;
+	let t42 = t29 ^ t33;
+	let t43 = t29 ^ t40;
+	let t44 = t33 ^ t37;
+	let t45 = t42 ^ t41;
+	let z0 = t44 & y15;
+	let z1 = t37 & y6;
+	let z2 = t33 & x7;
+	let z3 = t43 & y16;
+	let z4 = t40 & y1;
+	let z5 = t29 & y7;
+	let z6 = t42 & y11;
+	let z7 = t45 & y17;
+	let z8 = t41 & y10;
+	let z9 = t44 & y12;
+	let z10 = t37 & y3;
+	let z11 = t33 & y4;
+	let z12 = t43 & y13;
+	let z13 = t40 & y5;
+	let z14 = t29 & y2;
+	let z15 = t42 & y9;
+	let z16 = t45 & y14;
+	let z17 = t41 & y8;
+
+	// Bottom linear transformation
+	let t46 = z15 ^ z16;
+	let t47 = z10 ^ z11;
+	let t48 = z5 ^ z13;
+	let t49 = z9 ^ z10;
+	let t50 = z2 ^ z12;
+	let t51 = z2 ^ z5;
+	let t52 = z7 ^ z8;
+	let t53 = z0 ^ z3;
+	let t54 = z6 ^ z7;
+	let t55 = z16 ^ z17;
+	let t56 = z12 ^ t48;
+	let t57 = t50 ^ t53;
+	let t58 = z4 ^ t46;
+	let t59 = z3 ^ t54;
+	let t60 = t46 ^ t57;
+	let t61 = z14 ^ t57;
+	let t62 = t52 ^ t58;
+	let t63 = t49 ^ t58;
+	let t64 = z4 ^ t59;
+	let t65 = t61 ^ t62;
+	let t66 = z1 ^ t63;
+	let s0 = t59 ^ t63;
+	let s6 = t56 ^ !t62;
+	let s7 = t48 ^ !t60;
+	let t67 = t64 ^ t65;
+	let s3 = t53 ^ t66;
+	let s4 = t51 ^ t66;
+	let s5 = t47 ^ t65;
+	let s1 = t64 ^ !s3;
+	let s2 = t55 ^ !t67;
+
+	*q = [s7, s6, s5, s4, s3, s2, s1, s0];
+}
+
+/// Applies the inverse AES S-box to every byte
+///
+/// The inverse S-box is the inverse affine transformation, then the field inversion, then the inverse affine transformation again - The field inversion is done by the forward S-box between two inverse affine transformations, which cancel out its own affine transformation
+fn inv_sub_bytes(q: &mut Planes) {
+	inv_affine(q);
+	sub_bytes(q);
+	inv_affine(q);
+}
+
+/// The inverse of the affine transformation of the S-box (FIPS-197 Section 5.3.2): bit `i` becomes bits `i + 2`, `i + 5` and `i + 7` (mod 8) XORed together, XORed with bit `i` of 0x05
+fn inv_affine(q: &mut Planes) {
+	let x = *q;
+	*q = array::from_fn(|i| x[(i + 2) % 8] ^ x[(i + 5) % 8] ^ x[(i + 7) % 8]);
+	q[0] = !q[0];
+	q[2] = !q[2];
+}
+
+/// Permutes the lanes of every plane, lane `i` being taken from lane `perm[i]`
+fn shift_rows(q: &mut Planes, perm: &[usize; 16]) {
+	for plane in q.iter_mut() {
+		let lanes = plane.to_le_bytes();
+		*plane = u128::from_le_bytes(array::from_fn(|i| lanes[perm[i]]));
+	}
+}
+
+/// Moves each byte of a plane up `n` rows within its column (with wraparound), so that lane `r + 4c` is taken from lane `(r + n) % 4 + 4c`
+fn rotate_columns(x: u128, n: u32) -> u128 {
+	let mut res = 0;
+	for c in 0..4 {
+		res |= (((x >> (32 * c)) as u32).rotate_right(8 * n) as u128) << (32 * c);
+	}
+	res
+}
+
+/// Multiplies every byte by 2 in GF(2^8)
+fn xtime(q: &Planes) -> Planes {
+	[q[7], q[0] ^ q[7], q[1], q[2] ^ q[7], q[3] ^ q[7], q[4], q[5], q[6]]
+}
+
+fn mix_columns(q: &mut Planes) {
+	// Each byte becomes 2a_r + 3a_(r+1) + a_(r+2) + a_(r+3) = 2(a_r + a_(r+1)) + a_(r+1) + a_(r+2) + a_(r+3)
+	let r1 = q.map(|x| rotate_columns(x, 1));
+	let t = xtime(&array::from_fn(|b| q[b] ^ r1[b]));
+
+	*q = array::from_fn(|b| t[b] ^ r1[b] ^ rotate_columns(q[b], 2) ^ rotate_columns(q[b], 3));
+}
+
+fn inv_mix_columns(q: &mut Planes) {
+	// The InvMixColumns matrix is the MixColumns matrix multiplied by one that adds 4(a_r + a_(r+2)) to each byte
+	let t = xtime(&xtime(&array::from_fn(|b| q[b] ^ rotate_columns(q[b], 2))));
+	add_round_key(q, &t);
+
+	mix_columns(q);
+}
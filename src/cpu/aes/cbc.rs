@@ -0,0 +1,412 @@
+//! This module implements AES/CBC on the CPU with PKCS#7 padding or without padding, using x86/x86_64 AES-NI intrinsics if available
+//!
+//! CBC encryption is inherently sequential, but decryption of each block only depends on the ciphertext, so it can be parallelised using `AesCbcBlock::decompose` or `aes_cbc_decrypt_par`
+//!
+//! As with AES/CTR, `key` is taken as a little-endian array of bytes and `data` is taken as a little-endian array of little-endian 16-byte blocks. The `cbc_` methods of `ExpandedKey` take `key` and `data` in the byte order of the key instead
+
+use std::{error::Error, fmt, sync::Arc};
+
+use super::{random_iv, unwrap_or_panic, AesError, Backend, ByteOrder, ExpandedKey, RoundKeys};
+use crate::cpu::scoped_thread_pool::ThreadPool;
+
+#[cfg(test)]
+const KEY: [u8; 16] = 0x2b7e151628aed2a6abf7158809cf4f3cu128.to_le_bytes();
+#[cfg(test)]
+const IV: u128 = 0x000102030405060708090a0b0c0d0e0f;
+// Test vectors as big-endian blocks (Source: https://nvlpubs.nist.gov/nistpubs/Legacy/SP/nistspecialpublication800-38a.pdf)
+#[cfg(test)]
+const PLAINTEXT: [u128; 4] = [
+	0x6bc1bee22e409f96e93d7e117393172a,
+	0xae2d8a571e03ac9c9eb76fac45af8e51,
+	0x30c81c46a35ce411e5fbc1191a0a52ef,
+	0xf69f2445df4f9b17ad2b417be66c3710
+];
+#[cfg(test)]
+const CIPHERTEXT: [u128; 4] = [
+	0x7649abac8119b246cee98e9b12e9197d,
+	0x5086cb9b507219ee95db113a917678b2,
+	0x73bed6b8e3c1743b7116e69e22229516,
+	0x3ff1caa1681fac09120eca307586e1a7
+];
+
+#[cfg(test)]
+#[test]
+fn test_aes_cbc_nopad() {
+	// My implementation uses a little-endian array of little-endian blocks, so each block is converted with to_le_bytes
+	let plaintext: Vec<u8> = PLAINTEXT.iter().flat_map(|b| b.to_le_bytes()).collect();
+	let ciphertext: Vec<u8> = CIPHERTEXT.iter().flat_map(|b| b.to_le_bytes()).collect();
+
+	let mut input = plaintext.clone();
+
+	aes_cbc_encrypt_nopad(&mut input, &KEY, Some(IV));
+
+	assert_eq!(input, ciphertext, "[ERROR]: Computed ciphertext is not equal to expected ciphertext");
+
+	aes_cbc_decrypt_nopad(&mut input, &KEY, IV);
+
+	assert_eq!(input, plaintext, "[ERROR]: Decryption using IV that was used for encryption does not yeild exactly the plaintext");
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_cbc_pkcs7() {
+	let plaintext: Vec<u8> = PLAINTEXT.iter().flat_map(|b| b.to_le_bytes()).collect();
+
+	// Every possible padding length, including a full block of padding
+	for len in 0..=plaintext.len() {
+		let mut input = plaintext[..len].to_vec();
+
+		let iv = aes_cbc_encrypt(&mut input, &KEY, None);
+
+		assert_eq!(input.len(), (len / 16 + 1) * 16, "[ERROR]: Ciphertext is not padded to the next whole block");
+		if len == plaintext.len() {
+			let ciphertext: Vec<u8> = CIPHERTEXT.iter().flat_map(|b| b.to_le_bytes()).collect();
+			assert_ne!(&input[..len], &ciphertext[..], "[ERROR]: A random IV was not generated");
+		}
+
+		aes_cbc_decrypt(&mut input, &KEY, iv).expect("[ERROR]: Padding of a valid ciphertext was rejected");
+
+		assert_eq!(&input[..], &plaintext[..len], "[ERROR]: Decryption using IV that was used for encryption does not yeild exactly the plaintext");
+	}
+
+	// Decrypting with the wrong key produces invalid padding (for this fixed IV), and the data should then be left at its full length
+	let mut input = plaintext[..20].to_vec();
+	let iv = aes_cbc_encrypt(&mut input, &KEY, Some(IV));
+	let mut wrong_key = KEY;
+	wrong_key[0] ^= 1;
+	assert_eq!(aes_cbc_decrypt(&mut input, &wrong_key, iv), Err(PaddingError));
+	assert_eq!(input.len(), 32);
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_cbc_decrypt_par() {
+	let plaintext: Vec<u8> = (0..4099).map(|i| (i * 7) as u8).collect();
+
+	let mut pool = ThreadPool::new();
+
+	let mut input = plaintext.clone();
+	let iv = aes_cbc_encrypt(&mut input, &KEY, None);
+
+	let mut sequential = input.clone();
+	aes_cbc_decrypt(&mut sequential, &KEY, iv).unwrap();
+
+	aes_cbc_decrypt_par(&mut input, &KEY, iv, &mut pool).unwrap();
+
+	assert_eq!(input, sequential, "[ERROR]: Parallel decryption differs from sequential decryption");
+	assert_eq!(input, plaintext);
+
+	// Decompose into blocks and decrypt each in its own task, as with AesBlock
+	let ciphertext: Vec<u8> = CIPHERTEXT.iter().flat_map(|b| b.to_le_bytes()).collect();
+	let mut input = ciphertext.clone();
+
+	let blocks = AesCbcBlock::decompose(&mut input, &KEY, IV);
+
+	pool.scoped(|scope| {
+		for mut b in blocks {
+			scope.assign_task(move || {
+				b.decrypt();
+			});
+		}
+	});
+
+	assert_eq!(input, PLAINTEXT.iter().flat_map(|b| b.to_le_bytes()).collect::<Vec<u8>>());
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_cbc_expanded_key() {
+	// The NIST test vectors in standard byte order
+	let key = ExpandedKey::new(&0x2b7e151628aed2a6abf7158809cf4f3cu128.to_be_bytes(), ByteOrder::Standard);
+	let plaintext: Vec<u8> = PLAINTEXT.iter().flat_map(|b| b.to_be_bytes()).collect();
+	let ciphertext: Vec<u8> = CIPHERTEXT.iter().flat_map(|b| b.to_be_bytes()).collect();
+
+	let mut input = plaintext.clone();
+	key.cbc_encrypt_nopad(&mut input, Some(IV)).unwrap();
+	assert_eq!(input, ciphertext, "[ERROR]: Computed ciphertext is not equal to expected ciphertext");
+
+	key.cbc_decrypt_nopad_par(&mut input, IV, &mut ThreadPool::with_num_workers(2)).unwrap();
+	assert_eq!(input, plaintext, "[ERROR]: Decryption using IV that was used for encryption does not yeild exactly the plaintext");
+
+	// The same key is reused for several messages
+	for len in [0, 5, 16, 40] {
+		let mut input = plaintext[..len].to_vec();
+		let iv = key.cbc_encrypt(&mut input, None).unwrap();
+		key.cbc_decrypt(&mut input, iv).unwrap();
+		assert_eq!(&input[..], &plaintext[..len]);
+	}
+}
+
+/// The error returned when decrypted data does not end in valid PKCS#7 padding, which means the wrong key or IV was used or the data was corrupted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaddingError;
+
+impl fmt::Display for PaddingError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "invalid PKCS#7 padding - the wrong key or IV was used, or the data has been modified")
+	}
+}
+
+impl Error for PaddingError {}
+
+/// This struct contains the information necessary to decrypt one CBC block independently of the others
+pub struct AesCbcBlock<'a> {
+	prev_block: u128,
+	data: &'a mut [u8],
+	dec_round_keys: Arc<RoundKeys>,
+	backend: Backend,
+	order: ByteOrder
+}
+
+impl<'a> AesCbcBlock<'a> {
+	/// Creates a Vec of AesCbcBlocks, one for each 16-byte block of `data`, each holding a copy of the ciphertext block before it (or `iv` for the first) so that they can be decrypted in any order
+	/// # Panics
+	/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) or `data` is not a multiple of 16 bytes long - Use `AesCbcBlock::try_decompose` to get an `AesError` instead
+	pub fn decompose(data: &'a mut [u8], key: &[u8], iv: u128) -> Vec<AesCbcBlock<'a>> {
+		unwrap_or_panic(Self::try_decompose(data, key, iv))
+	}
+
+	/// The same as `AesCbcBlock::decompose`, but returns `AesError::InvalidKeyLength` or `AesError::InvalidDataLength` instead of panicking
+	pub fn try_decompose(data: &'a mut [u8], key: &[u8], iv: u128) -> Result<Vec<AesCbcBlock<'a>>, AesError> {
+		ExpandedKey::try_new(key, ByteOrder::Legacy)?.cbc_decompose(data, iv)
+	}
+
+	/// Decrypts the block in-place
+	pub fn decrypt(&mut self) {
+		let block = self.order.block_from_bytes()((*self.data).try_into().unwrap());
+		let plain = self.backend.inv_cipher(block, &self.dec_round_keys) ^ self.prev_block;
+		self.data.copy_from_slice(&self.order.block_bytes()(plain));
+	}
+}
+
+impl ExpandedKey {
+	/// Perform AES/CBC encryption on `data` with an IV if provided, padding `data` to a whole number of blocks with PKCS#7 padding, as `aes_cbc_encrypt` does
+	///
+	/// Returns the IV that needs to be stored alongside the encrypted data and used for decryption, or `AesError::EntropyFailure` without modifying `data` if `iv` is not provided and an RNG providing secure entropy could not be found/used by the `getrandom` crate
+	pub fn cbc_encrypt(&self, data: &mut Vec<u8>, iv: Option<u128>) -> Result<u128, AesError> {
+		let iv = match iv {
+			Some(iv) => iv,
+			None => random_iv()?
+		};
+
+		// PKCS#7: Pad with n bytes of value n, where n is between 1 and 16
+		let pad_len = 16 - (data.len() % 16);
+		data.resize(data.len() + pad_len, pad_len as u8);
+
+		self.cbc_encrypt_nopad(data, Some(iv))
+	}
+
+	/// Perform AES/CBC decryption on `data` with `iv` - The IV that was used for encryption, then remove the PKCS#7 padding, as `aes_cbc_decrypt` does
+	///
+	/// Returns `AesError::InvalidDataLength` without modifying `data` if it is not a multiple of 16 bytes long, or `AesError::InvalidPadding` with `data` left decrypted but not truncated if the padding is invalid
+	pub fn cbc_decrypt(&self, data: &mut Vec<u8>, iv: u128) -> Result<(), AesError> {
+		self.cbc_decrypt_nopad(data, iv)?;
+
+		Ok(unpad(data)?)
+	}
+
+	/// The same as `ExpandedKey::cbc_decrypt`, but decrypts `data` in parallel using the threads of `pool`
+	pub fn cbc_decrypt_par(&self, data: &mut Vec<u8>, iv: u128, pool: &mut ThreadPool) -> Result<(), AesError> {
+		self.cbc_decrypt_nopad_par(data, iv, pool)?;
+
+		Ok(unpad(data)?)
+	}
+
+	/// Perform AES/CBC encryption on slice `data`, which must be a whole number of blocks, without padding, as `aes_cbc_encrypt_nopad` does
+	///
+	/// Returns the IV that needs to be stored alongside the encrypted data and used for decryption, or `AesError::InvalidDataLength` or `AesError::EntropyFailure` without modifying `data`
+	pub fn cbc_encrypt_nopad(&self, data: &mut [u8], iv: Option<u128>) -> Result<u128, AesError> {
+		check_data_len(data)?;
+
+		// If provided, then we use that, if not provided, then we generate one
+		let iv = match iv {
+			Some(iv) => iv,
+			None => random_iv()?
+		};
+
+		let (from_bytes, to_bytes) = (self.order.block_from_bytes(), self.order.block_bytes());
+
+		// Each block is XORed with the previous ciphertext block (or the IV for the first) before encryption
+		let mut prev_block = iv;
+		for chunk in data.chunks_mut(16) {
+			prev_block = self.cipher(from_bytes((*chunk).try_into().unwrap()) ^ prev_block);
+			chunk.copy_from_slice(&to_bytes(prev_block));
+		}
+
+		Ok(iv)
+	}
+
+	/// Perform AES/CBC decryption on slice `data`, which must be a whole number of blocks, without removing padding, as `aes_cbc_decrypt_nopad` does
+	///
+	/// Returns `AesError::InvalidDataLength` without modifying `data` if it is not a multiple of 16 bytes long
+	pub fn cbc_decrypt_nopad(&self, data: &mut [u8], iv: u128) -> Result<(), AesError> {
+		check_data_len(data)?;
+
+		let (from_bytes, to_bytes) = (self.order.block_from_bytes(), self.order.block_bytes());
+
+		let mut prev_block = iv;
+		for chunk in data.chunks_mut(16) {
+			let block = from_bytes((*chunk).try_into().unwrap());
+			chunk.copy_from_slice(&to_bytes(self.inv_cipher(block) ^ prev_block));
+			prev_block = block;
+		}
+
+		Ok(())
+	}
+
+	/// The same as `ExpandedKey::cbc_decrypt_nopad`, but decrypts `data` in parallel using the threads of `pool`
+	///
+	/// `data` is split into one contiguous run of blocks per worker thread
+	pub fn cbc_decrypt_nopad_par(&self, data: &mut [u8], iv: u128, pool: &mut ThreadPool) -> Result<(), AesError> {
+		let mut blocks = self.cbc_decompose(data, iv)?;
+
+		// One task per worker rather than per block, as a task per block costs more than decrypting it
+		pool.scoped(|scope| {
+			scope.par_chunks_mut(&mut blocks, 1, |_, task_blocks| {
+				task_blocks.iter_mut().for_each(|b| b.decrypt());
+			});
+		});
+
+		Ok(())
+	}
+
+	/// Splits `data` into `AesCbcBlock`s that can be decrypted independently, as `AesCbcBlock::decompose` does - The blocks share this key's decryption round keys
+	///
+	/// Returns `AesError::InvalidDataLength` if `data` is not a multiple of 16 bytes long
+	pub fn cbc_decompose<'a>(&self, data: &'a mut [u8], iv: u128) -> Result<Vec<AesCbcBlock<'a>>, AesError> {
+		check_data_len(data)?;
+
+		let from_bytes = self.order.block_from_bytes();
+		let dec_round_keys = self.dec_round_keys();
+
+		// The previous ciphertext block of each block - Collected before any decryption happens, as decryption overwrites the ciphertext
+		let prev_blocks: Vec<u128> = std::iter::once(iv).chain(data.chunks(16).map(|c| from_bytes(c.try_into().unwrap()))).collect();
+
+		Ok(data.chunks_mut(16).zip(prev_blocks).map(|(data_chunk, prev_block)| {
+			AesCbcBlock { prev_block, data: data_chunk, dec_round_keys: dec_round_keys.clone(), backend: self.backend, order: self.order }
+		}).collect())
+	}
+}
+
+/// Perform AES/CBC encryption on `data` using slice `key` (`key` having gone through necessary key derivation and being exactly 128, 192 or 256-bit, which selects AES-128, AES-192 or AES-256) and an IV if provided, padding `data` to a whole number of blocks with PKCS#7 padding
+///
+/// Will use x86/x86_64 AES-NI intrinsics if available
+///
+/// Returns the IV that needs to be stored alongside the encrypted data and used for decryption. The data is padded and encrypted in-place. The IV must be unpredictable, so it should only be provided when it was generated securely
+///
+/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) or, if `iv` is not provided, an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `try_aes_cbc_encrypt` to get an `AesError` instead
+pub fn aes_cbc_encrypt(data: &mut Vec<u8>, key: &[u8], iv: Option<u128>) -> u128 {
+	unwrap_or_panic(try_aes_cbc_encrypt(data, key, iv))
+}
+
+/// The same as `aes_cbc_encrypt`, but returns `AesError::InvalidKeyLength` or `AesError::EntropyFailure` instead of panicking, without modifying `data`
+pub fn try_aes_cbc_encrypt(data: &mut Vec<u8>, key: &[u8], iv: Option<u128>) -> Result<u128, AesError> {
+	ExpandedKey::try_new(key, ByteOrder::Legacy)?.cbc_encrypt(data, iv)
+}
+
+/// Perform AES/CBC decryption on `data` using slice `key` (`key` having gone through necessary key derivation and being exactly 128, 192 or 256-bit) and `iv` - The IV that was used for encryption, then remove the PKCS#7 padding
+///
+/// Will use x86/x86_64 AES-NI intrinsics if available
+///
+/// The data is decrypted and truncated in-place. If the padding is invalid, `PaddingError` is returned and `data` is left decrypted but not truncated
+///
+/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) or `data` is not a multiple of 16 bytes long - Use `try_aes_cbc_decrypt` to get an `AesError` instead
+pub fn aes_cbc_decrypt(data: &mut Vec<u8>, key: &[u8], iv: u128) -> Result<(), PaddingError> {
+	padding_or_panic(try_aes_cbc_decrypt(data, key, iv))
+}
+
+/// The same as `aes_cbc_decrypt`, but returns `AesError::InvalidKeyLength` or `AesError::InvalidDataLength` instead of panicking, without modifying `data`, and `AesError::InvalidPadding` instead of `PaddingError`
+pub fn try_aes_cbc_decrypt(data: &mut Vec<u8>, key: &[u8], iv: u128) -> Result<(), AesError> {
+	ExpandedKey::try_new(key, ByteOrder::Legacy)?.cbc_decrypt(data, iv)
+}
+
+/// Perform AES/CBC decryption on `data` in parallel using the threads of `pool`, then remove the PKCS#7 padding. Otherwise the same as `aes_cbc_decrypt`
+///
+/// `data` is split into one contiguous run of blocks per worker thread
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) or `data` is not a multiple of 16 bytes long - Use `try_aes_cbc_decrypt_par` to get an `AesError` instead
+pub fn aes_cbc_decrypt_par(data: &mut Vec<u8>, key: &[u8], iv: u128, pool: &mut ThreadPool) -> Result<(), PaddingError> {
+	padding_or_panic(try_aes_cbc_decrypt_par(data, key, iv, pool))
+}
+
+/// The same as `aes_cbc_decrypt_par`, but returns the same errors as `try_aes_cbc_decrypt` instead of panicking
+pub fn try_aes_cbc_decrypt_par(data: &mut Vec<u8>, key: &[u8], iv: u128, pool: &mut ThreadPool) -> Result<(), AesError> {
+	ExpandedKey::try_new(key, ByteOrder::Legacy)?.cbc_decrypt_par(data, iv, pool)
+}
+
+/// Perform AES/CBC encryption on slice `data`, which must be a whole number of blocks, without padding. Otherwise the same as `aes_cbc_encrypt`
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), `data` is not a multiple of 16 bytes long or, if `iv` is not provided, an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `try_aes_cbc_encrypt_nopad` to get an `AesError` instead
+pub fn aes_cbc_encrypt_nopad(data: &mut [u8], key: &[u8], iv: Option<u128>) -> u128 {
+	unwrap_or_panic(try_aes_cbc_encrypt_nopad(data, key, iv))
+}
+
+/// The same as `aes_cbc_encrypt_nopad`, but returns `AesError::InvalidKeyLength`, `AesError::InvalidDataLength` or `AesError::EntropyFailure` instead of panicking, without modifying `data`
+pub fn try_aes_cbc_encrypt_nopad(data: &mut [u8], key: &[u8], iv: Option<u128>) -> Result<u128, AesError> {
+	ExpandedKey::try_new(key, ByteOrder::Legacy)?.cbc_encrypt_nopad(data, iv)
+}
+
+/// Perform AES/CBC decryption on slice `data`, which must be a whole number of blocks, without removing padding. Otherwise the same as `aes_cbc_decrypt`
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) or `data` is not a multiple of 16 bytes long - Use `try_aes_cbc_decrypt_nopad` to get an `AesError` instead
+pub fn aes_cbc_decrypt_nopad(data: &mut [u8], key: &[u8], iv: u128) {
+	unwrap_or_panic(try_aes_cbc_decrypt_nopad(data, key, iv));
+}
+
+/// The same as `aes_cbc_decrypt_nopad`, but returns `AesError::InvalidKeyLength` or `AesError::InvalidDataLength` instead of panicking, without modifying `data`
+pub fn try_aes_cbc_decrypt_nopad(data: &mut [u8], key: &[u8], iv: u128) -> Result<(), AesError> {
+	ExpandedKey::try_new(key, ByteOrder::Legacy)?.cbc_decrypt_nopad(data, iv)
+}
+
+/// Perform AES/CBC decryption on slice `data` in parallel using the threads of `pool`, without removing padding. Otherwise the same as `aes_cbc_decrypt_nopad`
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) or `data` is not a multiple of 16 bytes long - Use `try_aes_cbc_decrypt_nopad_par` to get an `AesError` instead
+pub fn aes_cbc_decrypt_nopad_par(data: &mut [u8], key: &[u8], iv: u128, pool: &mut ThreadPool) {
+	unwrap_or_panic(try_aes_cbc_decrypt_nopad_par(data, key, iv, pool));
+}
+
+/// The same as `aes_cbc_decrypt_nopad_par`, but returns `AesError::InvalidKeyLength` or `AesError::InvalidDataLength` instead of panicking, without modifying `data`
+pub fn try_aes_cbc_decrypt_nopad_par(data: &mut [u8], key: &[u8], iv: u128, pool: &mut ThreadPool) -> Result<(), AesError> {
+	ExpandedKey::try_new(key, ByteOrder::Legacy)?.cbc_decrypt_nopad_par(data, iv, pool)
+}
+
+/// Checks that `data` is a whole number of blocks
+fn check_data_len(data: &[u8]) -> Result<(), AesError> {
+	if data.len().is_multiple_of(16) {
+		Ok(())
+	} else {
+		Err(AesError::InvalidDataLength(data.len()))
+	}
+}
+
+/// Unwraps the result of a `try_` decryption function for the function that panics instead, except for `AesError::InvalidPadding`, which is returned as `PaddingError`
+fn padding_or_panic(res: Result<(), AesError>) -> Result<(), PaddingError> {
+	match res {
+		Ok(()) => Ok(()),
+		Err(AesError::InvalidPadding) => Err(PaddingError),
+		Err(e) => panic!("{}", e)
+	}
+}
+
+/// Validates and removes PKCS#7 padding from `data`
+fn unpad(data: &mut Vec<u8>) -> Result<(), PaddingError> {
+	let pad_len = *data.last().ok_or(PaddingError)? as usize;
+
+	if pad_len == 0 || pad_len > 16 || pad_len > data.len() {
+		return Err(PaddingError);
+	}
+
+	// Check every padding byte rather than stopping at the first bad one
+	let diff = data[(data.len() - pad_len)..].iter().fold(0, |acc, b| acc | (b ^ pad_len as u8));
+	if diff != 0 {
+		return Err(PaddingError);
+	}
+
+	data.truncate(data.len() - pad_len);
+
+	Ok(())
+}
@@ -0,0 +1,260 @@
+//! This module implements AES-GCM authenticated encryption (NIST SP 800-38D) on top of the AES/CTR keystream, using x86/x86_64 PCLMULQDQ intrinsics for GHASH if available
+//!
+//! Unlike the rest of the crate, the key, IV, data, associated data and tag are all taken in the standard byte order used by NIST and other AES implementations, as GHASH is defined over byte strings
+
+use std::{error::Error, fmt};
+
+use super::{apply_keystream, unwrap_or_panic, zeroize::zeroize, AesError, ByteOrder, ExpandedKey};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod simd;
+mod sisd;
+
+#[cfg(test)]
+fn hex(s: &str) -> Vec<u8> {
+	(0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).unwrap()).collect()
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_gcm() {
+	const K: &str = "feffe9928665731c6d6a8f9467308308";
+	const P: &str = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a721c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255";
+	const A: &str = "feedfacedeadbeeffeedfacedeadbeefabaddad2";
+
+	// (key, iv, plaintext, aad, ciphertext, tag) - Test cases 1-6 and 16 (Source: McGrew & Viega, The Galois/Counter Mode of Operation, Appendix B - the GCM specification submitted to NIST)
+	let cases: [(&str, &str, &str, &str, &str, &str); 7] = [
+		("00000000000000000000000000000000", "000000000000000000000000", "", "", "", "58e2fccefa7e3061367f1d57a4e7455a"),
+		("00000000000000000000000000000000", "000000000000000000000000", "00000000000000000000000000000000", "", "0388dace60b6a392f328c2b971b2fe78", "ab6e47d42cec13bdf53a67b21257bddf"),
+		(K, "cafebabefacedbaddecaf888", P, "", "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091473f5985", "4d5c2af327cd64a62cf35abd2ba6fab4"),
+		(K, "cafebabefacedbaddecaf888", &P[..120], A, "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091", "5bc94fbc3221a5db94fae95ae7121a47"),
+		(K, "cafebabefacedbad", &P[..120], A, "61353b4c2806934a777ff51fa22a4755699b2a714fcdc6f83766e5f97b6c742373806900e49f24b22b097544d4896b424989b5e1ebac0f07c23f4598", "3612d2e79e3b0785561be14aaca2fccb"),
+		(K, "9313225df88406e555909c5aff5269aa6a7a9538534f7da1e4c303d2a318a728c3c0c95156809539fcf0e2429a6b525416aedbf5a0de6a57a637b39b", &P[..120], A, "8ce24998625615b603a033aca13fb894be9112a5c3a211a8ba262a3cca7e2ca701e4a9a4fba43c90ccdcb281d48c7c6fd62875d2aca417034c34aee5", "619cc5aefffe0bfa462af43c1699d050"),
+		("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308", "cafebabefacedbaddecaf888", &P[..120], A, "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662", "76fc6ece0f4e1768cddf8853bb2d551b")
+	];
+
+	for (key, iv, plaintext, aad, ciphertext, tag) in cases {
+		let (key, iv, plaintext, aad, ciphertext, tag) = (hex(key), hex(iv), hex(plaintext), hex(aad), hex(ciphertext), hex(tag));
+
+		let mut input = plaintext.clone();
+
+		let computed_tag = aes_gcm_encrypt(&mut input, &key, &iv, &aad);
+
+		assert_eq!(input, ciphertext, "[ERROR]: Computed ciphertext is not equal to expected ciphertext");
+		assert_eq!(&computed_tag[..], &tag[..], "[ERROR]: Computed tag is not equal to expected tag");
+
+		aes_gcm_decrypt(&mut input, &key, &iv, &aad, &computed_tag).expect("[ERROR]: Decryption of an untampered ciphertext failed authentication");
+
+		assert_eq!(input, plaintext, "[ERROR]: Decryption does not yield exactly the plaintext");
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_gcm_tampered() {
+	let key = hex("feffe9928665731c6d6a8f9467308308");
+	let iv = hex("cafebabefacedbaddecaf888");
+	let aad = hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+
+	let mut ciphertext = b"Attack at dawn, from the north-east".to_vec();
+	let tag = aes_gcm_encrypt(&mut ciphertext, &key, &iv, &aad);
+
+	// Flipped ciphertext bit
+	let mut tampered = ciphertext.clone();
+	tampered[3] ^= 0x01;
+	assert_eq!(aes_gcm_decrypt(&mut tampered, &key, &iv, &aad, &tag), Err(AuthenticationError));
+	assert_eq!(tampered[3] ^ 0x01, ciphertext[3]);
+	assert_eq!(&tampered[4..], &ciphertext[4..], "[ERROR]: Data was modified despite failing authentication");
+
+	// Modified associated data
+	let mut input = ciphertext.clone();
+	assert_eq!(aes_gcm_decrypt(&mut input, &key, &iv, &aad[1..], &tag), Err(AuthenticationError));
+	assert_eq!(input, ciphertext, "[ERROR]: Data was modified despite failing authentication");
+
+	// Modified tag
+	let mut bad_tag = tag;
+	bad_tag[15] ^= 0x80;
+	assert_eq!(aes_gcm_decrypt(&mut input, &key, &iv, &aad, &bad_tag), Err(AuthenticationError));
+	assert_eq!(input, ciphertext, "[ERROR]: Data was modified despite failing authentication");
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[cfg(test)]
+#[test]
+fn test_gf_mul() {
+	use rand::{RngCore, SeedableRng};
+	use rand_chacha::ChaCha20Rng;
+
+	let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+	for _ in 0..1000 {
+		let mut bytes = [0u8; 32];
+		rng.fill_bytes(&mut bytes);
+		let x = u128::from_le_bytes(bytes[..16].try_into().unwrap());
+		let y = u128::from_le_bytes(bytes[16..].try_into().unwrap());
+
+		let clmul_res = unsafe { simd::gf_mul(x, y) };
+		let portable_res = sisd::gf_mul(x, y);
+
+		assert_eq!(clmul_res, portable_res, "[ERROR]: The two implementation produce different results");
+	}
+}
+
+/// The error returned by `aes_gcm_decrypt` when the authentication tag does not match the ciphertext and associated data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthenticationError;
+
+impl fmt::Display for AuthenticationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "authentication tag mismatch - the ciphertext or associated data has been modified, or the wrong key or IV was used")
+	}
+}
+
+impl Error for AuthenticationError {}
+
+/// Perform AES-GCM authenticated encryption on slice `data` using slice `key` (being exactly 128, 192 or 256-bit, which selects AES-128, AES-192 or AES-256), IV `iv` and additional authenticated data `aad`
+///
+/// Will use x86/x86_64 AES-NI and PCLMULQDQ intrinsics if available
+///
+/// Returns the 16-byte authentication tag that needs to be stored alongside the encrypted data and checked during decryption. The data is encrypted in-place. `aad` is authenticated but not encrypted
+///
+/// `iv` may be any non-zero length, but 12 bytes is recommended. An IV must never be used twice with the same key
+///
+/// All arguments are taken in standard (FIPS-197/SP 800-38D) byte order
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), `iv` is empty, or `data` is longer than 2^36 - 32 bytes - Use `try_aes_gcm_encrypt` to get an `AesError` instead
+pub fn aes_gcm_encrypt(data: &mut [u8], key: &[u8], iv: &[u8], aad: &[u8]) -> [u8; 16] {
+	unwrap_or_panic(try_aes_gcm_encrypt(data, key, iv, aad))
+}
+
+/// The same as `aes_gcm_encrypt`, but returns `AesError::InvalidKeyLength`, `AesError::EmptyIv` or `AesError::InvalidDataLength` instead of panicking, without modifying `data`
+pub fn try_aes_gcm_encrypt(data: &mut [u8], key: &[u8], iv: &[u8], aad: &[u8]) -> Result<[u8; 16], AesError> {
+	ExpandedKey::try_new(key, ByteOrder::Standard)?.gcm_encrypt(data, iv, aad)
+}
+
+/// Perform AES-GCM authenticated decryption on slice `data` using slice `key` (being exactly 128, 192 or 256-bit), IV `iv`, additional authenticated data `aad` and the 16-byte authentication tag `tag` returned by encryption
+///
+/// Will use x86/x86_64 AES-NI and PCLMULQDQ intrinsics if available
+///
+/// The tag is verified before any decryption takes place. If it matches, the data is decrypted in-place. If it doesn't, `AuthenticationError` is returned and `data` is left untouched, so no unauthenticated plaintext is ever released
+///
+/// All arguments are taken in standard (FIPS-197/SP 800-38D) byte order
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), `iv` is empty, or `data` is longer than 2^36 - 32 bytes - Use `try_aes_gcm_decrypt` to get an `AesError` instead
+pub fn aes_gcm_decrypt(data: &mut [u8], key: &[u8], iv: &[u8], aad: &[u8], tag: &[u8; 16]) -> Result<(), AuthenticationError> {
+	match try_aes_gcm_decrypt(data, key, iv, aad, tag) {
+		Ok(()) => Ok(()),
+		Err(AesError::AuthenticationFailure) => Err(AuthenticationError),
+		Err(e) => panic!("{}", e)
+	}
+}
+
+/// The same as `aes_gcm_decrypt`, but returns `AesError::InvalidKeyLength`, `AesError::EmptyIv` or `AesError::InvalidDataLength` instead of panicking, and `AesError::AuthenticationFailure` instead of `AuthenticationError` - `data` is left untouched if an error is returned
+pub fn try_aes_gcm_decrypt(data: &mut [u8], key: &[u8], iv: &[u8], aad: &[u8], tag: &[u8; 16]) -> Result<(), AesError> {
+	ExpandedKey::try_new(key, ByteOrder::Standard)?.gcm_decrypt(data, iv, aad, tag)
+}
+
+impl ExpandedKey {
+	/// Perform AES-GCM authenticated encryption on slice `data` with IV `iv` and additional authenticated data `aad`, as `aes_gcm_encrypt` does - The data, IV, associated data and tag are taken in standard byte order whatever the byte order of the key
+	///
+	/// Returns the 16-byte authentication tag, or `AesError::EmptyIv` or `AesError::InvalidDataLength` without modifying `data`
+	pub fn gcm_encrypt(&self, data: &mut [u8], iv: &[u8], aad: &[u8]) -> Result<[u8; 16], AesError> {
+		check_args(data, iv)?;
+
+		// Hash subkey and pre-counter block
+		let mut h = self.cipher(0);
+		let j0 = pre_counter_block(h, iv);
+
+		apply_keystream(data, self, (1..=u32::MAX).map(|n| inc32(j0, n)), u128::to_be_bytes);
+
+		let tag = (ghash(h, aad, data) ^ self.cipher(j0)).to_be_bytes();
+		// The hash subkey is derived from the key, so it is wiped like the round keys
+		zeroize(std::slice::from_mut(&mut h));
+
+		Ok(tag)
+	}
+
+	/// Perform AES-GCM authenticated decryption on slice `data` with IV `iv`, additional authenticated data `aad` and the authentication tag `tag`, as `aes_gcm_decrypt` does
+	///
+	/// Returns `AesError::AuthenticationFailure` if the tag does not match, or `AesError::EmptyIv` or `AesError::InvalidDataLength` - `data` is left untouched if an error is returned
+	pub fn gcm_decrypt(&self, data: &mut [u8], iv: &[u8], aad: &[u8], tag: &[u8; 16]) -> Result<(), AesError> {
+		check_args(data, iv)?;
+
+		// Hash subkey and pre-counter block
+		let mut h = self.cipher(0);
+		let j0 = pre_counter_block(h, iv);
+
+		let expected_tag = (ghash(h, aad, data) ^ self.cipher(j0)).to_be_bytes();
+		zeroize(std::slice::from_mut(&mut h));
+
+		// Compare every byte rather than returning at the first difference, so the comparison doesn't leak how much of the tag was correct
+		let diff = expected_tag.iter().zip(tag).fold(0, |acc, (a, b)| acc | (a ^ b));
+		if diff != 0 {
+			return Err(AesError::AuthenticationFailure);
+		}
+
+		apply_keystream(data, self, (1..=u32::MAX).map(|n| inc32(j0, n)), u128::to_be_bytes);
+
+		Ok(())
+	}
+}
+
+/// Checks that `iv` is not empty and `data` is no longer than 2^36 - 32 bytes, the most GCM can encrypt with one IV
+fn check_args(data: &[u8], iv: &[u8]) -> Result<(), AesError> {
+	if iv.is_empty() {
+		return Err(AesError::EmptyIv);
+	}
+	if data.len() as u64 > (1 << 36) - 32 {
+		return Err(AesError::InvalidDataLength(data.len()));
+	}
+
+	Ok(())
+}
+
+/// Derives the pre-counter block J0 from the IV: `iv || 0^31 || 1` if the IV is 96 bits, otherwise the GHASH of the IV
+fn pre_counter_block(h: u128, iv: &[u8]) -> u128 {
+	if iv.len() == 12 {
+		let mut j0 = [0u8; 16];
+		j0[..12].copy_from_slice(iv);
+		j0[15] = 1;
+		u128::from_be_bytes(j0)
+	} else {
+		// GHASH(IV || 0^(s + 64) || [len(IV)]_64) is the same as GHASH with empty associated data and the IV in place of the ciphertext
+		ghash(h, &[], iv)
+	}
+}
+
+/// Increments the rightmost 32 bits of `block` by `n`, modulo 2^32
+fn inc32(block: u128, n: u32) -> u128 {
+	(block & !0xffffffff) | ((block as u32).wrapping_add(n) as u128)
+}
+
+/// Computes GHASH with hash subkey `h` over `aad` and `ciphertext`, each zero-padded to a multiple of 16 bytes, followed by the block of their lengths in bits
+fn ghash(h: u128, aad: &[u8], ciphertext: &[u8]) -> u128 {
+	let mut y = 0;
+
+	for data in [aad, ciphertext] {
+		for chunk in data.chunks(16) {
+			let mut block = [0u8; 16];
+			block[..chunk.len()].copy_from_slice(chunk);
+			y = gf_mul(y ^ u128::from_be_bytes(block), h);
+		}
+	}
+
+	let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+	gf_mul(y ^ lengths, h)
+}
+
+/// Multiplies two elements of GF(2^128) as defined for GHASH
+///
+/// Will use x86/x86_64 PCLMULQDQ intrinsics if available
+fn gf_mul(x: u128, y: u128) -> u128 {
+	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+	{
+		if is_x86_feature_detected!("pclmulqdq") && is_x86_feature_detected!("sse2") {
+			return unsafe { simd::gf_mul(x, y) };
+		}
+	}
+
+	sisd::gf_mul(x, y)
+}
@@ -0,0 +1,70 @@
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__m128i, _mm_clmulepi64_si128, _mm_or_si128, _mm_slli_epi32, _mm_slli_si128, _mm_srli_epi32, _mm_srli_si128, _mm_xor_si128};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__m128i, _mm_clmulepi64_si128, _mm_or_si128, _mm_slli_epi32, _mm_slli_si128, _mm_srli_epi32, _mm_srli_si128, _mm_xor_si128};
+
+/// Transforms a rust u128 to an SSE __m128i using std::mem::transmute
+unsafe fn to_sse_128(n: u128) -> __m128i {
+	std::mem::transmute(n)
+}
+
+/// Transforms a SSE __m128i to a rust u128 using std::mem::transmute
+unsafe fn from_sse_128(n: __m128i) -> u128 {
+	std::mem::transmute(n)
+}
+
+/// Multiplies two elements of GF(2^128) as defined for GHASH, with blocks taken as big-endian u128s, using the `_mm_clmulepi64_si128` (PCLMULQDQ) intrinsic
+///
+/// A big-endian u128 transmuted into an __m128i is the byte-reflected block that the Intel carry-less multiplication white paper's `gfmul` algorithm expects, so no shuffling is needed
+#[target_feature(enable = "pclmulqdq,sse2")]
+pub unsafe fn gf_mul(x: u128, y: u128) -> u128 {
+	let a = to_sse_128(x);
+	let b = to_sse_128(y);
+
+	// 256-bit carry-less product of a and b in (xmm6:xmm3) using 4 64-bit multiplications
+	let mut xmm3 = _mm_clmulepi64_si128::<0x00>(a, b);
+	let mut xmm4 = _mm_clmulepi64_si128::<0x10>(a, b);
+	let mut xmm5 = _mm_clmulepi64_si128::<0x01>(a, b);
+	let mut xmm6 = _mm_clmulepi64_si128::<0x11>(a, b);
+
+	xmm4 = _mm_xor_si128(xmm4, xmm5);
+	xmm5 = _mm_slli_si128::<8>(xmm4);
+	xmm4 = _mm_srli_si128::<8>(xmm4);
+	xmm3 = _mm_xor_si128(xmm3, xmm5);
+	xmm6 = _mm_xor_si128(xmm6, xmm4);
+
+	// Shift the product left by one bit, because the operands are bit-reflected
+	let mut xmm7 = _mm_srli_epi32::<31>(xmm3);
+	let mut xmm8 = _mm_srli_epi32::<31>(xmm6);
+	xmm3 = _mm_slli_epi32::<1>(xmm3);
+	xmm6 = _mm_slli_epi32::<1>(xmm6);
+
+	let mut xmm9 = _mm_srli_si128::<12>(xmm7);
+	xmm8 = _mm_slli_si128::<4>(xmm8);
+	xmm7 = _mm_slli_si128::<4>(xmm7);
+	xmm3 = _mm_or_si128(xmm3, xmm7);
+	xmm6 = _mm_or_si128(xmm6, xmm8);
+	xmm6 = _mm_or_si128(xmm6, xmm9);
+
+	// Reduce modulo x^128 + x^7 + x^2 + x + 1
+	xmm7 = _mm_slli_epi32::<31>(xmm3);
+	xmm8 = _mm_slli_epi32::<30>(xmm3);
+	xmm9 = _mm_slli_epi32::<25>(xmm3);
+
+	xmm7 = _mm_xor_si128(xmm7, xmm8);
+	xmm7 = _mm_xor_si128(xmm7, xmm9);
+	xmm8 = _mm_srli_si128::<4>(xmm7);
+	xmm7 = _mm_slli_si128::<12>(xmm7);
+	xmm3 = _mm_xor_si128(xmm3, xmm7);
+
+	let mut xmm2 = _mm_srli_epi32::<1>(xmm3);
+	xmm4 = _mm_srli_epi32::<2>(xmm3);
+	xmm5 = _mm_srli_epi32::<7>(xmm3);
+	xmm2 = _mm_xor_si128(xmm2, xmm4);
+	xmm2 = _mm_xor_si128(xmm2, xmm5);
+	xmm2 = _mm_xor_si128(xmm2, xmm8);
+	xmm3 = _mm_xor_si128(xmm3, xmm2);
+	xmm6 = _mm_xor_si128(xmm6, xmm3);
+
+	from_sse_128(xmm6)
+}
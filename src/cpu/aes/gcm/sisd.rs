@@ -0,0 +1,21 @@
+/// The GCM reduction polynomial (x^128 + x^7 + x^2 + x + 1) in GCM's bit-reflected representation, excluding the x^128 term
+const R: u128 = 0xe1 << 120;
+
+/// Multiplies two elements of GF(2^128) as defined for GHASH (NIST SP 800-38D Algorithm 1), with blocks taken as big-endian u128s
+///
+/// Branches on data are replaced with masks so that the running time doesn't depend on the inputs
+pub fn gf_mul(x: u128, y: u128) -> u128 {
+	let mut z = 0u128;
+	let mut v = y;
+
+	for i in 0..128 {
+		// All ones if bit i of x (counting from the most significant) is set, otherwise all zeros
+		let x_i = 0u128.wrapping_sub((x >> (127 - i)) & 1);
+		z ^= v & x_i;
+
+		let v_lsb = 0u128.wrapping_sub(v & 1);
+		v = (v >> 1) ^ (R & v_lsb);
+	}
+
+	z
+}
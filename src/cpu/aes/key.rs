@@ -0,0 +1,285 @@
+//! This module implements `ExpandedKey`, an AES key that is expanded once and can then encrypt or decrypt any number of messages, in any mode
+//!
+//! The AES/CTR methods are implemented here, and the AES/CBC and AES-GCM methods in the `cbc` and `gcm` modules. The free functions of each mode create an `ExpandedKey` for every call, so use an `ExpandedKey` directly to encrypt many messages under the same key
+
+use std::sync::{Arc, OnceLock};
+
+use super::{apply_keystream, apply_keystream_at, apply_keystream_par, check_key_len, unwrap_or_panic, AesBlock, AesCtr, AesError, Backend, ByteOrder, CounterLayout, RoundKeys};
+use crate::cpu::scoped_thread_pool::ThreadPool;
+
+#[cfg(test)]
+#[test]
+fn test_expanded_key() {
+	const KEY: [u8; 16] = 0x2b7e151628aed2a6abf7158809cf4f3cu128.to_be_bytes();
+	const IV: u128 = 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff;
+
+	let plaintext: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+
+	let mut pool = ThreadPool::with_num_workers(4);
+
+	for order in [ByteOrder::Legacy, ByteOrder::Standard] {
+		// Shared between threads, each encrypting its own messages
+		let key = Arc::new(ExpandedKey::new(&KEY, order));
+
+		let mut expected = plaintext.clone();
+		super::aes_encrypt_decrypt_with_order(&mut expected, &KEY, Some(IV), order);
+
+		pool.scoped(|scope| {
+			for _ in 0..4 {
+				let key = key.clone();
+				let (plaintext, expected) = (&plaintext, &expected);
+				scope.assign_task(move || {
+					let mut data = plaintext.clone();
+					key.encrypt_decrypt(&mut data, Some(IV)).unwrap();
+					assert_eq!(&data, expected, "[ERROR]: Encrypting with an ExpandedKey differs from aes_encrypt_decrypt_with_order");
+				});
+			}
+		});
+
+		let mut data = plaintext.clone();
+		key.encrypt_decrypt_par(&mut data, Some(IV), &mut pool).unwrap();
+		assert_eq!(data, expected);
+
+		let mut data = plaintext.clone();
+		let mut blocks = key.decompose(&mut data, Some(IV)).unwrap();
+		AesBlock::encrypt_batch(&mut blocks);
+		drop(blocks);
+		assert_eq!(data, expected);
+
+		let mut data = expected[100..300].to_vec();
+		key.encrypt_decrypt_at(&mut data, IV, 100, CounterLayout::FULL).unwrap();
+		assert_eq!(&data[..], &plaintext[100..300]);
+
+		let mut data = plaintext.clone();
+		key.ctr(IV, CounterLayout::FULL).apply_keystream(&mut data).unwrap();
+		assert_eq!(data, expected);
+
+		let mut data = expected.clone();
+		key.decrypt(&mut data, IV).unwrap();
+		assert_eq!(data, plaintext);
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_with_backend() {
+	const KEY: [u8; 32] = [0x60; 32];
+	const IV: u128 = 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff;
+
+	// Long enough for the wide VAES kernels and a remainder
+	let plaintext: Vec<u8> = (0..1000).map(|i| (i * 13) as u8).collect();
+
+	assert!(Backend::supported().contains(&Backend::detect()), "[ERROR]: The detected backend is not supported");
+	assert!(Backend::supported().contains(&Backend::PORTABLE));
+	assert_eq!(ExpandedKey::new(&KEY, ByteOrder::Standard).backend(), Backend::detect());
+
+	// The process-wide default is used by new keys until it is reset
+	Backend::set_default(Some(Backend::Bitsliced)).unwrap();
+	assert_eq!(ExpandedKey::new(&KEY, ByteOrder::Standard).backend(), Backend::Bitsliced, "[ERROR]: The default backend was not used");
+	Backend::set_default(None).unwrap();
+	assert_eq!(Backend::current(), Backend::detect());
+
+	let expected = ExpandedKey::with_backend(&KEY, ByteOrder::Standard, Backend::Sisd).unwrap();
+	let mut expected_ctr = plaintext.clone();
+	expected.encrypt_decrypt(&mut expected_ctr, Some(IV)).unwrap();
+	let mut expected_cbc = plaintext.clone();
+	expected.cbc_encrypt(&mut expected_cbc, Some(IV)).unwrap();
+	let mut expected_gcm = plaintext.clone();
+	let expected_tag = expected.gcm_encrypt(&mut expected_gcm, &[0; 12], &[]).unwrap();
+
+	for &backend in Backend::ALL {
+		let key = match ExpandedKey::with_backend(&KEY, ByteOrder::Standard, backend) {
+			Ok(key) => key,
+			Err(e) => {
+				assert!(!backend.is_supported(), "[ERROR]: A supported backend could not be used");
+				assert_eq!(e, AesError::UnsupportedBackend(backend));
+				continue;
+			}
+		};
+		assert_eq!(key.backend(), backend);
+
+		let mut data = plaintext.clone();
+		key.encrypt_decrypt(&mut data, Some(IV)).unwrap();
+		assert_eq!(data, expected_ctr, "[ERROR]: The {} backend differs from the portable one for AES/CTR", backend);
+
+		let mut data = plaintext.clone();
+		key.cbc_encrypt(&mut data, Some(IV)).unwrap();
+		assert_eq!(data, expected_cbc, "[ERROR]: The {} backend differs from the portable one for AES/CBC", backend);
+		key.cbc_decrypt(&mut data, IV).unwrap();
+		assert_eq!(data, plaintext);
+
+		let mut data = plaintext.clone();
+		let tag = key.gcm_encrypt(&mut data, &[0; 12], &[]).unwrap();
+		assert_eq!((data, tag), (expected_gcm.clone(), expected_tag), "[ERROR]: The {} backend differs from the portable one for AES-GCM", backend);
+	}
+}
+
+/// An AES key that has been expanded into round keys, for encrypting or decrypting many messages under the same key without re-running the key expansion
+///
+/// The AES implementation (`Backend`) is picked once, when the key is created. The round keys are held in an `Arc`, so cloning an `ExpandedKey` is cheap, and it can be shared between threads by reference or in an `Arc` - The round keys are zeroed when the last clone (or `AesBlock` using them) is dropped
+///
+/// The key is taken in the byte order given when it is created, and the AES/CTR and AES/CBC methods take data in that byte order too. AES-GCM always takes data in the standard byte order
+#[derive(Clone)]
+pub struct ExpandedKey {
+	pub(super) round_keys: Arc<RoundKeys>,
+	/// The decryption round keys, which are only expanded the first time they are needed (by AES/CBC decryption)
+	dec_round_keys: OnceLock<Arc<RoundKeys>>,
+	pub(super) order: ByteOrder,
+	pub(super) backend: Backend
+}
+
+impl ExpandedKey {
+	/// Expands slice `key` (`key` having gone through necessary key derivation and being exactly 128, 192 or 256-bit, which selects AES-128, AES-192 or AES-256), with `key` and data taken in byte order `order`
+	/// # Panics
+	/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) - Use `ExpandedKey::try_new` to get an `AesError` instead
+	pub fn new(key: &[u8], order: ByteOrder) -> ExpandedKey {
+		unwrap_or_panic(ExpandedKey::try_new(key, order))
+	}
+
+	/// The same as `ExpandedKey::new`, but returns `AesError::InvalidKeyLength` instead of panicking
+	pub fn try_new(key: &[u8], order: ByteOrder) -> Result<ExpandedKey, AesError> {
+		ExpandedKey::with_backend(key, order, Backend::current())
+	}
+
+	/// The same as `ExpandedKey::try_new`, but uses `backend` instead of `Backend::current` - The output is the same whatever the backend, so this is for testing and benchmarking, or for using the constant-time `Backend::Bitsliced` for one key
+	///
+	/// Returns `AesError::UnsupportedBackend` if the CPU does not support `backend`, or `AesError::InvalidKeyLength`
+	pub fn with_backend(key: &[u8], order: ByteOrder, backend: Backend) -> Result<ExpandedKey, AesError> {
+		check_key_len(key)?;
+		if !backend.is_supported() {
+			return Err(AesError::UnsupportedBackend(backend));
+		}
+
+		let round_keys = Arc::new(backend.key_expansion(&order.key_bytes(key)));
+
+		Ok(ExpandedKey { round_keys, dec_round_keys: OnceLock::new(), order, backend })
+	}
+
+	/// Returns the byte order that the key and data are taken in
+	pub fn order(&self) -> ByteOrder {
+		self.order
+	}
+
+	/// Returns the backend that encrypts and decrypts with this key
+	pub fn backend(&self) -> Backend {
+		self.backend
+	}
+
+	/// Returns the decryption round keys, expanding them if this is the first time they are needed
+	pub(super) fn dec_round_keys(&self) -> &Arc<RoundKeys> {
+		self.dec_round_keys.get_or_init(|| Arc::new(self.backend.inv_key_expansion(&self.round_keys)))
+	}
+
+	/// Performs the cipher on a 128-bit state
+	pub(super) fn cipher(&self, state: u128) -> u128 {
+		self.backend.cipher(state, &self.round_keys)
+	}
+
+	/// Performs the cipher on each 128-bit state in `states` in-place
+	pub(super) fn cipher_blocks(&self, states: &mut [u128]) {
+		self.backend.cipher_blocks(states, &self.round_keys)
+	}
+
+	/// Performs the inverse cipher on a 128-bit state
+	pub(super) fn inv_cipher(&self, state: u128) -> u128 {
+		self.backend.inv_cipher(state, self.dec_round_keys())
+	}
+
+	/// Perform AES/CTR encryption on slice `data` with a new random IV, as `aes_encrypt` does
+	///
+	/// Returns the IV that needs to be stored alongside the encrypted data and used for decryption, or `AesError::EntropyFailure` without modifying `data` if an RNG providing secure entropy could not be found/used by the `getrandom` crate
+	pub fn encrypt(&self, data: &mut [u8]) -> Result<u128, AesError> {
+		self.encrypt_decrypt(data, None)
+	}
+
+	/// Perform AES/CTR decryption on slice `data` with `iv` - The IV that was used for encryption, as `aes_decrypt` does
+	///
+	/// Returns `AesError::CounterOverflow` without modifying `data` if the counter would wrap around past `u128::MAX`
+	pub fn decrypt(&self, data: &mut [u8], iv: u128) -> Result<(), AesError> {
+		self.encrypt_decrypt(data, Some(iv)).map(|_| ())
+	}
+
+	/// Perform AES/CTR encryption/decryption on slice `data` with an IV if provided, as `aes_encrypt_decrypt` does - The whole 128-bit counter block is the counter, the same as `ExpandedKey::encrypt_decrypt_with_layout` with `CounterLayout::FULL`
+	///
+	/// Returns the IV that needs to be stored alongside the encrypted data and used for decryption, or `AesError::CounterOverflow` or `AesError::EntropyFailure` without modifying `data`
+	pub fn encrypt_decrypt(&self, data: &mut [u8], iv: Option<u128>) -> Result<u128, AesError> {
+		self.encrypt_decrypt_with_layout(data, iv, CounterLayout::FULL)
+	}
+
+	/// Perform AES/CTR encryption/decryption on slice `data` in parallel using the threads of `pool`, as `aes_encrypt_decrypt_par` does. Otherwise the same as `ExpandedKey::encrypt_decrypt`, and the output is identical
+	pub fn encrypt_decrypt_par(&self, data: &mut [u8], iv: Option<u128>, pool: &mut ThreadPool) -> Result<u128, AesError> {
+		self.encrypt_decrypt_par_with_layout(data, iv, CounterLayout::FULL, pool)
+	}
+
+	/// Perform AES/CTR encryption/decryption on slice `data` with the counter laid out in the counter block according to `layout` (see `CounterLayout`) - If `iv` is not provided, one is generated with the counter set to 0
+	///
+	/// Returns the IV that needs to be stored alongside the encrypted data and used for decryption, or `AesError::CounterOverflow` or `AesError::EntropyFailure` without modifying `data`
+	pub fn encrypt_decrypt_with_layout(&self, data: &mut [u8], iv: Option<u128>, layout: CounterLayout) -> Result<u128, AesError> {
+		let iv = match iv {
+			Some(iv) => iv,
+			None => layout.random_iv()?
+		};
+
+		let num_128_blks = data.len().div_ceil(16);
+		layout.check(iv, num_128_blks as u128)?;
+
+		let counter = (0..num_128_blks).map(|n| layout.block(iv, n as u128));
+		apply_keystream(data, self, counter, self.order.block_bytes());
+
+		Ok(iv)
+	}
+
+	/// The same as `ExpandedKey::encrypt_decrypt_with_layout`, but encrypts `data` in parallel using the threads of `pool`
+	pub fn encrypt_decrypt_par_with_layout(&self, data: &mut [u8], iv: Option<u128>, layout: CounterLayout, pool: &mut ThreadPool) -> Result<u128, AesError> {
+		let iv = match iv {
+			Some(iv) => iv,
+			None => layout.random_iv()?
+		};
+
+		layout.check(iv, data.len().div_ceil(16) as u128)?;
+
+		apply_keystream_par(data, self, |n| layout.block(iv, n), self.order.block_bytes(), pool);
+
+		Ok(iv)
+	}
+
+	/// Perform AES/CTR encryption/decryption on slice `data`, which is the part of a larger message starting at byte `offset`, as `aes_encrypt_decrypt_at` does
+	///
+	/// Returns `AesError::CounterOverflow` without modifying `data` if the end of `data` is past the last block the counter can count from the IV's counter
+	pub fn encrypt_decrypt_at(&self, data: &mut [u8], iv: u128, offset: u64, layout: CounterLayout) -> Result<(), AesError> {
+		apply_keystream_at(data, self, iv, offset, layout, self.order.block_bytes())
+	}
+
+	/// Splits `data` into `AesBlock`s that can be encrypted independently, as `AesBlock::decompose_with_order` does - The blocks share this key's round keys
+	///
+	/// Returns `AesError::CounterOverflow` if the counter would wrap around past `u128::MAX`, or `AesError::EntropyFailure` if `iv` is not provided and an RNG providing secure entropy could not be found/used by the `getrandom` crate
+	pub fn decompose<'a>(&self, data: &'a mut [u8], iv: Option<u128>) -> Result<Vec<AesBlock<'a>>, AesError> {
+		self.decompose_with_layout(data, iv, CounterLayout::FULL)
+	}
+
+	/// Splits `data` into `AesBlock`s with the counter laid out in the counter block according to `layout`, as `ExpandedKey::encrypt_decrypt_with_layout` does
+	///
+	/// Returns `AesError::CounterOverflow` if `data` has more blocks than the counter can count from the IV's counter, or `AesError::EntropyFailure` if `iv` is not provided and an RNG providing secure entropy could not be found/used by the `getrandom` crate
+	pub fn decompose_with_layout<'a>(&self, data: &'a mut [u8], iv: Option<u128>, layout: CounterLayout) -> Result<Vec<AesBlock<'a>>, AesError> {
+		let iv = match iv {
+			Some(iv) => iv,
+			None => layout.random_iv()?
+		};
+
+		layout.check(iv, data.len().div_ceil(16) as u128)?;
+
+		Ok(data.chunks_mut(16).enumerate().map(|(n, data_chunk)| AesBlock::new(self, layout.block(iv, n as u128), data_chunk)).collect())
+	}
+
+	/// Creates an `AesCtr` at the start of a message encrypted with this key and `iv`, with the counter laid out according to `layout` - The round keys are shared with the `AesCtr`
+	pub fn ctr(&self, iv: u128, layout: CounterLayout) -> AesCtr {
+		AesCtr::from_key(self.clone(), iv, layout)
+	}
+
+	/// Creates an `AesCtr` at the start of a new message with a random IV, as `AesCtr::with_random_iv` does
+	///
+	/// Returns `AesError::EntropyFailure` if an RNG providing secure entropy could not be found/used by the `getrandom` crate
+	pub fn ctr_with_random_iv(&self, layout: CounterLayout) -> Result<AesCtr, AesError> {
+		Ok(self.ctr(layout.random_iv()?, layout))
+	}
+}
@@ -1,15 +1,29 @@
-//! This module implements AES-128/CTR on the CPU, using x86/x86_64 AES-NI intrinsics if available
+//! This module implements AES/CTR (AES-128, AES-192 and AES-256, picked by key length) on the CPU, using x86/x86_64 AES-NI intrinsics if available, and VAES intrinsics on wide vector registers for bulk encryption if available
 //!
-//! Parallelisation is available using `AesBlock::decompose` and passing them into different threads
+//! Parallelisation is available using `aes_encrypt_decrypt_par`, or using `AesBlock::decompose` and passing them into different threads
 
-use std::sync::Arc;
+use std::{error::Error, fmt, ops::Deref, sync::{atomic::{AtomicUsize, Ordering}, Arc, OnceLock}};
 
-use rand::{RngCore, SeedableRng};
+use rand::{rngs::OsRng, RngCore, SeedableRng};
 use rand_chacha::{self, ChaCha20Rng};
 
+use super::scoped_thread_pool::ThreadPool;
+
+pub use key::ExpandedKey;
+use zeroize::Zeroizing;
+
+mod bitsliced;
+pub mod cbc;
+pub mod gcm;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod simd;
+mod key;
 mod sisd;
+pub mod stream;
+mod ttable;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod vaes;
+mod zeroize;
 
 #[cfg(test)]
 #[test]
@@ -109,6 +123,640 @@ fn test_cipher() {
 	assert_eq!(aesni_res, EXPECTED, "[ERROR]: Both implementations produce the same result but differ from the expected result");
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[cfg(test)]
+#[test]
+fn test_key_expansion_192() {
+	const KEY: [u64; 3] = [0x8e73b0f7da0e6452, 0xc810f32b809079e5, 0x62f8ead2522c6b7b];
+	// Source: FIPS-197 Appendix A.2
+	const EXPECTED: [u128; 13] = [
+		0x8e73b0f7da0e6452c810f32b809079e5,
+		0x62f8ead2522c6b7bfe0c91f72402f5a5,
+		0xec12068e6c827f6b0e7a95b95c56fec2,
+		0x4db7b4bd69b5411885a74796e92538fd,
+		0xe75fad44bb095386485af05721efb14f,
+		0xa448f6d94d6dce24aa326360113b30e6,
+		0xa25e7ed583b1cf9a27f939436a94f767,
+		0xc0a69407d19da4e1ec1786eb6fa64971,
+		0x485f703222cb8755e26d135233f0b7b3,
+		0x40beeb282f18a2596747d26b458c553e,
+		0xa7e1466c9411f1df821f750aad07d753,
+		0xca4005388fcc5006282d166abc3ce7b5,
+		0xe98ba06f448c773c8ecc720401002202
+	];
+
+	let aesni_res = unsafe {
+		simd::key_expansion_192(KEY)
+	};
+
+	let aesrs_res = sisd::key_expansion_192(KEY);
+
+	assert_eq!(aesni_res, aesrs_res, "[ERROR]: The two implementation produce different results");
+	assert_eq!(aesni_res, EXPECTED, "[ERROR]: Both implementations produce the same result but differ from the expected result");
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[cfg(test)]
+#[test]
+fn test_key_expansion_256() {
+	const KEY: [u128; 2] = [0x603deb1015ca71be2b73aef0857d7781, 0x1f352c073b6108d72d9810a30914dff4];
+	// Source: FIPS-197 Appendix A.3
+	const EXPECTED: [u128; 15] = [
+		0x603deb1015ca71be2b73aef0857d7781,
+		0x1f352c073b6108d72d9810a30914dff4,
+		0x9ba354118e6925afa51a8b5f2067fcde,
+		0xa8b09c1a93d194cdbe49846eb75d5b9a,
+		0xd59aecb85bf3c917fee94248de8ebe96,
+		0xb5a9328a2678a647983122292f6c79b3,
+		0x812c81addadf48ba24360af2fab8b464,
+		0x98c5bfc9bebd198e268c3ba709e04214,
+		0x68007bacb2df331696e939e46c518d80,
+		0xc814e20476a9fb8a5025c02d59c58239,
+		0xde1369676ccc5a71fa2563959674ee15,
+		0x5886ca5d2e2f31d77e0af1fa27cf73c3,
+		0x749c47ab18501ddae2757e4f7401905a,
+		0xcafaaae3e4d59b349adf6acebd10190d,
+		0xfe4890d1e6188d0b046df344706c631e
+	];
+
+	let aesni_res = unsafe {
+		simd::key_expansion_256(KEY)
+	};
+
+	let aesrs_res = sisd::key_expansion_256(KEY);
+
+	assert_eq!(aesni_res, aesrs_res, "[ERROR]: The two implementation produce different results");
+	assert_eq!(aesni_res, EXPECTED, "[ERROR]: Both implementations produce the same result but differ from the expected result");
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[cfg(test)]
+#[test]
+fn test_cipher_192_256() {
+	// Source: FIPS-197 Appendix C.2 and C.3
+	const INPUT: u128 = 0x00112233445566778899aabbccddeeff;
+	const KEY_192: [u64; 3] = [0x0001020304050607, 0x08090a0b0c0d0e0f, 0x1011121314151617];
+	const EXPECTED_192: u128 = 0xdda97ca4864cdfe06eaf70a0ec0d7191;
+	const KEY_256: [u128; 2] = [0x000102030405060708090a0b0c0d0e0f, 0x101112131415161718191a1b1c1d1e1f];
+	const EXPECTED_256: u128 = 0x8ea2b7ca516745bfeafc49904b496089;
+
+	let aesni_res = unsafe {
+		(simd::cipher(INPUT, &simd::key_expansion_192(KEY_192)), simd::cipher(INPUT, &simd::key_expansion_256(KEY_256)))
+	};
+
+	let aesrs_res = (sisd::cipher(INPUT, &sisd::key_expansion_192(KEY_192)), sisd::cipher(INPUT, &sisd::key_expansion_256(KEY_256)));
+
+	assert_eq!(aesni_res, aesrs_res, "[ERROR]: The two implementation produce different results");
+	assert_eq!(aesni_res, (EXPECTED_192, EXPECTED_256), "[ERROR]: Both implementations produce the same result but differ from the expected result");
+}
+
+#[cfg(test)]
+#[test]
+fn test_backends_fips197() {
+	use rand::Rng;
+
+	// Source: FIPS-197 Appendix C.1, C.2 and C.3 - The keys are the bytes 00, 01, 02, ... in order
+	const PLAINTEXT: u128 = 0x00112233445566778899aabbccddeeff;
+	const CASES: [(u8, u128); 3] = [
+		(16, 0x69c4e0d86a7b0430d8cdb78070b4c55a),
+		(24, 0xdda97ca4864cdfe06eaf70a0ec0d7191),
+		(32, 0x8ea2b7ca516745bfeafc49904b496089)
+	];
+
+	let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+	for (key_len, ciphertext) in CASES {
+		let key: Vec<u8> = (0..key_len).collect();
+		let expected_rks = Backend::Sisd.key_expansion(&key);
+		let expected_dec_rks = Backend::Sisd.inv_key_expansion(&expected_rks);
+
+		for backend in Backend::supported() {
+			let rks = backend.key_expansion(&key);
+			assert_eq!(&rks[..], &expected_rks[..], "[ERROR]: The {} key expansion differs from the table-based one", backend);
+			let dec_rks = backend.inv_key_expansion(&rks);
+			assert_eq!(&dec_rks[..], &expected_dec_rks[..], "[ERROR]: The {} inverse key expansion differs from the table-based one", backend);
+
+			assert_eq!(backend.cipher(PLAINTEXT, &rks), ciphertext, "[ERROR]: The {} cipher differs from the expected result", backend);
+			assert_eq!(backend.inv_cipher(ciphertext, &dec_rks), PLAINTEXT, "[ERROR]: The {} inverse cipher differs from the expected result", backend);
+
+			// Enough blocks for a full batch of the widest kernel and a remainder
+			let mut blocks = [PLAINTEXT; 35];
+			backend.cipher_blocks(&mut blocks, &rks);
+			assert_eq!(blocks, [ciphertext; 35], "[ERROR]: The {} batched cipher differs from the expected result", backend);
+
+			for _ in 0..100 {
+				let state: u128 = rng.gen();
+				let res = backend.cipher(state, &rks);
+
+				assert_eq!(res, Backend::Sisd.cipher(state, &expected_rks), "[ERROR]: The {} cipher differs from the table-based one", backend);
+				assert_eq!(backend.inv_cipher(res, &dec_rks), state, "[ERROR]: The {} inverse cipher does not invert the cipher", backend);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_bitsliced_blocks() {
+	use rand::Rng;
+
+	let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+	// Every byte value appears in the first 16 states, so every S-box input is exercised
+	let all_bytes: Vec<u128> = (0..16u8).map(|i| u128::from_be_bytes(std::array::from_fn(|j| i * 16 + j as u8))).collect();
+
+	for key_len in [16, 24, 32] {
+		let key: Vec<u8> = (0..key_len).map(|_| rng.gen()).collect();
+		let rks = Backend::detect().key_expansion(&key);
+		let dec_rks = sisd::inv_key_expansion(&rks);
+
+		// Random lengths so that both full batches of 8 and the remainder are exercised
+		for i in 0..20 {
+			let states: Vec<u128> = if i == 0 { all_bytes.clone() } else { (0..rng.gen_range(0..40)).map(|_| rng.gen()).collect() };
+
+			let expected: Vec<u128> = states.iter().map(|s| sisd::cipher(*s, &rks)).collect();
+
+			let mut res = states.clone();
+			bitsliced::cipher_blocks(&mut res, &rks);
+			assert_eq!(res, expected, "[ERROR]: The bitsliced implementation differs from the table-based implementation");
+
+			bitsliced::inv_cipher_blocks(&mut res, &dec_rks);
+			assert_eq!(res, states, "[ERROR]: The bitsliced inverse cipher does not invert the cipher");
+		}
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_encrypt_decrypt_standard() {
+	// Source: NIST SP 800-38A F.5.1 and F.5.5, unmodified
+	const KEY_128: [u8; 16] = 0x2b7e151628aed2a6abf7158809cf4f3cu128.to_be_bytes();
+	const KEY_256: [u8; 32] = [
+		0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d, 0x77, 0x81,
+		0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3, 0x09, 0x14, 0xdf, 0xf4
+	];
+	const IV: u128 = 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff;
+	const PLAINTEXT: [u8; 64] = [
+		0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a,
+		0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac, 0x45, 0xaf, 0x8e, 0x51,
+		0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11, 0xe5, 0xfb, 0xc1, 0x19, 0x1a, 0x0a, 0x52, 0xef,
+		0xf6, 0x9f, 0x24, 0x45, 0xdf, 0x4f, 0x9b, 0x17, 0xad, 0x2b, 0x41, 0x7b, 0xe6, 0x6c, 0x37, 0x10
+	];
+	const CIPHERTEXT_128: [u8; 64] = [
+		0x87, 0x4d, 0x61, 0x91, 0xb6, 0x20, 0xe3, 0x26, 0x1b, 0xef, 0x68, 0x64, 0x99, 0x0d, 0xb6, 0xce,
+		0x98, 0x06, 0xf6, 0x6b, 0x79, 0x70, 0xfd, 0xff, 0x86, 0x17, 0x18, 0x7b, 0xb9, 0xff, 0xfd, 0xff,
+		0x5a, 0xe4, 0xdf, 0x3e, 0xdb, 0xd5, 0xd3, 0x5e, 0x5b, 0x4f, 0x09, 0x02, 0x0d, 0xb0, 0x3e, 0xab,
+		0x1e, 0x03, 0x1d, 0xda, 0x2f, 0xbe, 0x03, 0xd1, 0x79, 0x21, 0x70, 0xa0, 0xf3, 0x00, 0x9c, 0xee
+	];
+	const CIPHERTEXT_256: [u8; 64] = [
+		0x60, 0x1e, 0xc3, 0x13, 0x77, 0x57, 0x89, 0xa5, 0xb7, 0xa7, 0xf5, 0x04, 0xbb, 0xf3, 0xd2, 0x28,
+		0xf4, 0x43, 0xe3, 0xca, 0x4d, 0x62, 0xb5, 0x9a, 0xca, 0x84, 0xe9, 0x90, 0xca, 0xca, 0xf5, 0xc5,
+		0x2b, 0x09, 0x30, 0xda, 0xa2, 0x3d, 0xe9, 0x4c, 0xe8, 0x70, 0x17, 0xba, 0x2d, 0x84, 0x98, 0x8d,
+		0xdf, 0xc9, 0xc5, 0x8d, 0xb6, 0x7a, 0xad, 0xa6, 0x13, 0xc2, 0xdd, 0x08, 0x45, 0x79, 0x41, 0xa6
+	];
+
+	let mut pool = ThreadPool::with_num_workers(3);
+
+	for (key, ciphertext) in [(&KEY_128[..], CIPHERTEXT_128), (&KEY_256[..], CIPHERTEXT_256)] {
+		// Also cropped, to check a partial last block
+		for len in [64, 60] {
+			let mut sequential = PLAINTEXT[..len].to_vec();
+			aes_encrypt_decrypt_with_order(&mut sequential, key, Some(IV), ByteOrder::Standard);
+			assert_eq!(&sequential[..], &ciphertext[..len], "[ERROR]: Computed ciphertext is not equal to expected ciphertext");
+
+			let mut parallel = PLAINTEXT[..len].to_vec();
+			aes_encrypt_decrypt_par_with_order(&mut parallel, key, Some(IV), ByteOrder::Standard, &mut pool);
+			assert_eq!(&parallel[..], &ciphertext[..len], "[ERROR]: Computed ciphertext is not equal to expected ciphertext");
+
+			let mut blocks_input = PLAINTEXT[..len].to_vec();
+			let mut blocks = AesBlock::decompose_with_order(&mut blocks_input, key, Some(IV), ByteOrder::Standard);
+			AesBlock::encrypt_batch(&mut blocks);
+			drop(blocks);
+			assert_eq!(&blocks_input[..], &ciphertext[..len], "[ERROR]: Computed ciphertext is not equal to expected ciphertext");
+
+			aes_encrypt_decrypt_with_order(&mut sequential, key, Some(IV), ByteOrder::Standard);
+			assert_eq!(&sequential[..], &PLAINTEXT[..len]);
+		}
+	}
+
+	// The legacy layout is the standard one with the key and each block byte-reversed
+	let mut standard = PLAINTEXT.to_vec();
+	aes_encrypt_decrypt_with_order(&mut standard, &KEY_128, Some(IV), ByteOrder::Standard);
+
+	let mut legacy: Vec<u8> = PLAINTEXT.chunks(16).flat_map(|b| b.iter().rev().copied()).collect();
+	let legacy_key: Vec<u8> = KEY_128.iter().rev().copied().collect();
+	aes_encrypt_decrypt(&mut legacy, &legacy_key, Some(IV));
+
+	assert_eq!(legacy.chunks(16).flat_map(|b| b.iter().rev().copied()).collect::<Vec<u8>>(), standard);
+}
+
+#[cfg(test)]
+#[test]
+fn test_counter_layout() {
+	// Source: RFC 3686 Section 6, Test Vectors #1 and #2 - The counter block is a 32-bit nonce, 64-bit IV and 32-bit counter starting at 1
+	let cases: [(u128, u128, Vec<u8>, Vec<u8>); 2] = [
+		(
+			0xae6852f8121067cc4bf7a5765577f39e,
+			0x00000030000000000000000000000001,
+			b"Single block msg".to_vec(),
+			0xe4095d4fb7a7b3792d6175a3261311b8u128.to_be_bytes().to_vec()
+		),
+		(
+			0x7e24067817fae0d743d6ce1f32539163,
+			0x006cb6dbc0543b59da48d90b00000001,
+			(0..32).collect(),
+			[0x5104a106168a72d9790d41ee8edad388u128.to_be_bytes(), 0xeb2e1efc46da57c8fce630df9141be28u128.to_be_bytes()].concat()
+		)
+	];
+
+	let mut pool = ThreadPool::with_num_workers(2);
+
+	for (key, iv, plaintext, ciphertext) in cases {
+		let key = key.to_be_bytes();
+
+		let mut sequential = plaintext.clone();
+		let res = ExpandedKey::new(&key, ByteOrder::Standard).encrypt_decrypt_with_layout(&mut sequential, Some(iv), CounterLayout::NONCE_96_COUNTER_32);
+		assert_eq!(res, Ok(iv));
+		assert_eq!(sequential, ciphertext, "[ERROR]: Computed ciphertext is not equal to expected ciphertext");
+
+		let mut parallel = plaintext.clone();
+		ExpandedKey::new(&key, ByteOrder::Standard).encrypt_decrypt_par_with_layout(&mut parallel, Some(iv), CounterLayout::NONCE_96_COUNTER_32, &mut pool).unwrap();
+		assert_eq!(parallel, ciphertext, "[ERROR]: Computed ciphertext is not equal to expected ciphertext");
+
+		let mut blocks_input = plaintext.clone();
+		let mut blocks = ExpandedKey::new(&key, ByteOrder::Standard).decompose_with_layout(&mut blocks_input, Some(iv), CounterLayout::NONCE_96_COUNTER_32).unwrap();
+		AesBlock::encrypt_batch(&mut blocks);
+		drop(blocks);
+		assert_eq!(blocks_input, ciphertext, "[ERROR]: Computed ciphertext is not equal to expected ciphertext");
+	}
+
+	const KEY: [u8; 16] = [0x2b; 16];
+	let key = ExpandedKey::new(&KEY, ByteOrder::Standard);
+	let mut pool = ThreadPool::with_num_workers(4);
+
+	// The counter reaches its largest value but does not wrap into the nonce
+	let nonce = 0x0123456789abcdef01234567u128 << 32;
+	let mut data = vec![0u8; 32];
+	key.encrypt_decrypt_with_layout(&mut data, Some(nonce | 0xfffffffe), CounterLayout::NONCE_96_COUNTER_32).unwrap();
+	let mut last_block = vec![0u8; 16];
+	key.encrypt_decrypt_with_layout(&mut last_block, Some(nonce | 0xffffffff), CounterLayout::NONCE_96_COUNTER_32).unwrap();
+	assert_eq!(&data[16..], &last_block[..]);
+
+	// One more block would wrap the counter, so nothing is encrypted
+	let overflows: [(CounterLayout, u128); 4] = [
+		(CounterLayout::NONCE_96_COUNTER_32, nonce | 0xfffffffe),
+		(CounterLayout::NONCE_64_COUNTER_64, (nonce << 32) | u64::MAX as u128),
+		(CounterLayout::FULL, u128::MAX - 1),
+		(CounterLayout::new(16, 32), 0xffff << 32)
+	];
+
+	for (layout, iv) in overflows {
+		let mut data = vec![0u8; 33];
+		assert_eq!(key.encrypt_decrypt_with_layout(&mut data, Some(iv), layout), Err(AesError::CounterOverflow));
+		assert_eq!(key.encrypt_decrypt_par_with_layout(&mut data, Some(iv), layout, &mut pool), Err(AesError::CounterOverflow));
+		assert!(key.decompose_with_layout(&mut data, Some(iv), layout).is_err());
+		assert_eq!(data, vec![0u8; 33], "[ERROR]: Data was modified despite the counter overflowing");
+	}
+
+	// A counter in the middle of the counter block leaves the bits on either side alone
+	let layout = CounterLayout::new(16, 32);
+	let iv = 0xaaaaaaaa_aaaaaaaa_aaaafffe_aaaaaaaa;
+	let mut blocks_input = vec![0u8; 32];
+	let blocks = key.decompose_with_layout(&mut blocks_input, Some(iv), layout).unwrap();
+	assert_eq!(blocks[1].counter(), 0xaaaaaaaa_aaaaaaaa_aaaaffff_aaaaaaaa);
+	drop(blocks);
+
+	// Generated IVs start the counter at 0
+	let iv = key.encrypt_decrypt_with_layout(&mut [0u8; 16], None, CounterLayout::NONCE_96_COUNTER_32).unwrap();
+	assert_eq!(iv & 0xffffffff, 0);
+
+	// The default functions count with the whole counter block, and report wrapping past `u128::MAX` too
+	let mut data = vec![0u8; 48];
+	assert_eq!(try_aes_encrypt_decrypt(&mut data, &KEY, Some(u128::MAX)), Err(AesError::CounterOverflow));
+	assert_eq!(try_aes_encrypt_decrypt_par(&mut data, &KEY, Some(u128::MAX), &mut pool), Err(AesError::CounterOverflow));
+	assert!(AesBlock::try_decompose(&mut data, &KEY, Some(u128::MAX)).is_err());
+	assert_eq!(data, vec![0u8; 48], "[ERROR]: Data was modified despite the counter overflowing");
+	assert_eq!(try_aes_decrypt(&mut data[..16], &KEY, u128::MAX), Ok(()));
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_encrypt_decrypt_at() {
+	const KEY: [u8; 24] = [0x8e; 24];
+	const IV: u128 = 0xf0f1f2f3f4f5f6f7f8f9fafb00000000;
+
+	let plaintext: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+
+	for layout in [CounterLayout::FULL, CounterLayout::NONCE_96_COUNTER_32] {
+		for order in [ByteOrder::Legacy, ByteOrder::Standard] {
+			let mut ciphertext = plaintext.clone();
+			ExpandedKey::new(&KEY, order).encrypt_decrypt_with_layout(&mut ciphertext, Some(IV), layout).unwrap();
+
+			// Aligned and unaligned starts and ends, ranges within a single block, and empty ranges
+			for (start, end) in [(0, 1000), (0, 5), (16, 32), (3, 13), (5, 21), (17, 500), (999, 1000), (64, 64), (100, 999), (15, 16)] {
+				let mut part = plaintext[start..end].to_vec();
+				aes_encrypt_decrypt_at(&mut part, &KEY, IV, start as u64, layout, order).unwrap();
+				assert_eq!(&part[..], &ciphertext[start..end], "[ERROR]: Encrypting bytes {}..{} differs from encrypting the whole message", start, end);
+
+				aes_encrypt_decrypt_at(&mut part, &KEY, IV, start as u64, layout, order).unwrap();
+				assert_eq!(&part[..], &plaintext[start..end]);
+			}
+		}
+	}
+
+	// The last block the counter can reach is fine, but the block after it is not
+	let iv = IV | 0xffffffff;
+	let layout = CounterLayout::NONCE_96_COUNTER_32;
+	assert!(aes_encrypt_decrypt_at(&mut [0u8; 1], &KEY, iv, 15, layout, ByteOrder::Standard).is_ok());
+	let mut data = [0u8; 2];
+	assert_eq!(aes_encrypt_decrypt_at(&mut data, &KEY, iv, 15, layout, ByteOrder::Standard), Err(AesError::CounterOverflow));
+	assert_eq!(data, [0u8; 2], "[ERROR]: Data was modified despite the counter overflowing");
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_ctr() {
+	use rand::Rng;
+
+	const KEY: [u8; 32] = [0x60; 32];
+	const IV: u128 = 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff;
+
+	let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+	let plaintext: Vec<u8> = (0..2000).map(|_| rng.gen()).collect();
+
+	for (layout, order) in [(CounterLayout::FULL, ByteOrder::Legacy), (CounterLayout::NONCE_64_COUNTER_64, ByteOrder::Standard)] {
+		let mut expected = plaintext.clone();
+		ExpandedKey::new(&KEY, order).encrypt_decrypt_with_layout(&mut expected, Some(IV), layout).unwrap();
+
+		// Random splits, including empty pieces and pieces within a single block
+		for _ in 0..20 {
+			let mut ctr = AesCtr::new(&KEY, IV, layout, order);
+			let mut streamed = plaintext.clone();
+
+			let mut rest = &mut streamed[..];
+			while !rest.is_empty() {
+				let (piece, tail) = rest.split_at_mut(rng.gen_range(0..=rest.len().min(100)));
+				ctr.apply_keystream(piece).unwrap();
+				rest = tail;
+			}
+
+			assert_eq!(ctr.position(), plaintext.len() as u64);
+			assert_eq!(streamed, expected, "[ERROR]: Streamed encryption differs from encrypting the whole message");
+		}
+
+		// One byte at a time, using the cached keystream of each block for all but its first byte
+		let mut ctr = AesCtr::new(&KEY, IV, layout, order);
+		let mut streamed = plaintext.clone();
+		for byte in streamed.chunks_mut(1) {
+			ctr.apply_keystream(byte).unwrap();
+		}
+		assert_eq!(streamed, expected, "[ERROR]: Byte-at-a-time encryption differs from encrypting the whole message");
+
+		// Decrypting from the middle
+		let mut ctr = AesCtr::new(&KEY, IV, layout, order);
+		ctr.seek(1001);
+		let mut part = expected[1001..1500].to_vec();
+		ctr.apply_keystream(&mut part).unwrap();
+		assert_eq!(&part[..], &plaintext[1001..1500]);
+	}
+
+	// A random IV starts the counter at 0
+	let mut ctr = AesCtr::with_random_iv(&KEY, CounterLayout::NONCE_96_COUNTER_32, ByteOrder::Standard);
+	assert_eq!(ctr.iv() & 0xffffffff, 0);
+
+	// The counter overflowing does not advance the position
+	ctr.seek(0xffffffff * 16);
+	ctr.apply_keystream(&mut [0u8; 16]).unwrap();
+	let mut data = [0u8; 1];
+	assert_eq!(ctr.apply_keystream(&mut data), Err(AesError::CounterOverflow));
+	assert_eq!(data, [0u8; 1]);
+	assert_eq!(ctr.position(), 0x100000000 * 16);
+
+	// The full 128-bit counter outlasts the 64-bit position, which must not wrap around to reuse the start of the keystream
+	let mut ctr = AesCtr::new(&KEY, 0, CounterLayout::FULL, ByteOrder::Standard);
+	ctr.seek(u64::MAX - 3);
+	let mut data = [0u8; 16];
+	assert_eq!(ctr.apply_keystream(&mut data), Err(AesError::CounterOverflow));
+	assert_eq!(ctr.apply_keystream_par(&mut data, &mut ThreadPool::with_num_workers(2)), Err(AesError::CounterOverflow));
+	assert_eq!(data, [0u8; 16]);
+	assert_eq!(ctr.position(), u64::MAX - 3);
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_error() {
+	let mut pool = ThreadPool::with_num_workers(2);
+
+	let plaintext = [0x5au8; 40];
+
+	// Invalid key lengths are reported without touching the data
+	for len in [0, 15, 17, 33] {
+		let key = vec![0x2b; len];
+		let mut data = plaintext;
+
+		assert_eq!(try_aes_encrypt(&mut data, &key), Err(AesError::InvalidKeyLength(len)));
+		assert_eq!(try_aes_decrypt(&mut data, &key, 0), Err(AesError::InvalidKeyLength(len)));
+		assert_eq!(try_aes_encrypt_decrypt_par(&mut data, &key, None, &mut pool), Err(AesError::InvalidKeyLength(len)));
+		assert_eq!(aes_encrypt_decrypt_at(&mut data, &key, 0, 5, CounterLayout::FULL, ByteOrder::Standard), Err(AesError::InvalidKeyLength(len)));
+		assert!(AesBlock::try_decompose(&mut data, &key, None).is_err());
+		assert!(AesCtr::try_new(&key, 0, CounterLayout::FULL, ByteOrder::Standard).is_err());
+
+		let mut vec = plaintext.to_vec();
+		assert_eq!(cbc::try_aes_cbc_encrypt(&mut vec, &key, None), Err(AesError::InvalidKeyLength(len)));
+		assert_eq!(vec, plaintext, "[ERROR]: Data was padded despite the key being rejected");
+		assert_eq!(gcm::try_aes_gcm_encrypt(&mut data, &key, &[0; 12], &[]), Err(AesError::InvalidKeyLength(len)));
+
+		assert_eq!(data, plaintext, "[ERROR]: Data was modified despite the key being rejected");
+	}
+
+	// The try_ variants give the same result as the functions that panic
+	const KEY: [u8; 16] = [0x2b; 16];
+	let mut expected = plaintext;
+	let iv = aes_encrypt(&mut expected, &KEY);
+	let mut data = plaintext;
+	assert_eq!(try_aes_encrypt_decrypt(&mut data, &KEY, Some(iv)), Ok(iv));
+	assert_eq!(data, expected);
+
+	// Mode-specific errors
+	let mut data = plaintext;
+	assert_eq!(cbc::try_aes_cbc_encrypt_nopad(&mut data, &KEY, None), Err(AesError::InvalidDataLength(40)));
+	assert_eq!(cbc::try_aes_cbc_decrypt_nopad(&mut data[..32], &KEY, 0), Ok(()));
+	assert_eq!(gcm::try_aes_gcm_encrypt(&mut data, &KEY, &[], &[]), Err(AesError::EmptyIv));
+
+	let mut vec = plaintext.to_vec();
+	let iv = cbc::aes_cbc_encrypt(&mut vec, &KEY, None);
+	vec[47] ^= 0xff;
+	assert_eq!(cbc::try_aes_cbc_decrypt(&mut vec, &KEY, iv), Err(AesError::InvalidPadding));
+
+	let mut data = plaintext;
+	let mut tag = gcm::try_aes_gcm_encrypt(&mut data, &KEY, &[0; 12], &[]).unwrap();
+	tag[0] ^= 1;
+	assert_eq!(gcm::try_aes_gcm_decrypt(&mut data, &KEY, &[0; 12], &[], &tag), Err(AesError::AuthenticationFailure));
+
+	// The old error types convert into AesError
+	assert_eq!(AesError::from(cbc::PaddingError), AesError::InvalidPadding);
+	assert_eq!(AesError::from(gcm::AuthenticationError), AesError::AuthenticationFailure);
+}
+
+#[cfg(test)]
+#[test]
+fn test_aes_encrypt_decrypt_par() {
+	const KEY: [u8; 32] = [0x42; 32];
+	const IV: u128 = 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff;
+
+	let mut pool = ThreadPool::with_num_workers(4);
+
+	// Lengths with fewer blocks than workers, a partial last block, and chunks that don't divide evenly between workers
+	for len in [0, 1, 15, 16, 17, 48, 60, 1000, 4096, 65535] {
+		let plaintext: Vec<u8> = (0..len).map(|i| (i * 13) as u8).collect();
+
+		let mut sequential = plaintext.clone();
+		aes_encrypt_decrypt(&mut sequential, &KEY, Some(IV));
+
+		let mut parallel = plaintext.clone();
+		let iv = aes_encrypt_decrypt_par(&mut parallel, &KEY, Some(IV), &mut pool);
+
+		assert_eq!(iv, IV);
+		assert_eq!(parallel, sequential, "[ERROR]: Parallel encryption differs from sequential encryption for length {}", len);
+
+		aes_encrypt_decrypt_par(&mut parallel, &KEY, Some(IV), &mut pool);
+
+		assert_eq!(parallel, plaintext);
+	}
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[cfg(test)]
+#[test]
+fn test_cipher_blocks() {
+	const KEYS: [&[u8]; 3] = [&[0x2b; 16], &[0x8e; 24], &[0x60; 32]];
+
+	// Enough states for two full pipelines and a remainder
+	let states: Vec<u128> = (0..19).map(|n| 0x6bc1bee22e409f96e93d7e117393172a ^ ((n as u128) << 64)).collect();
+
+	for key in KEYS {
+		let rks = Backend::detect().key_expansion(key);
+
+		let expected: Vec<u128> = states.iter().map(|s| sisd::cipher(*s, &rks)).collect();
+
+		let mut aesni_res = states.clone();
+		unsafe { simd::cipher_blocks(&mut aesni_res, &rks) };
+
+		assert_eq!(aesni_res, expected, "[ERROR]: The pipelined kernel differs from the one-block implementation");
+	}
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[cfg(test)]
+#[test]
+fn test_cipher_blocks_vaes() {
+	use rand::Rng;
+
+	let vaes_512 = Backend::Vaes512.is_supported();
+	let vaes_256 = Backend::Vaes256.is_supported();
+
+	if !vaes_512 && !vaes_256 {
+		println!("Skipping test_cipher_blocks_vaes: CPU does not support VAES");
+		return;
+	}
+
+	let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+	for key_len in [16, 24, 32] {
+		let key: Vec<u8> = (0..key_len).map(|_| rng.gen()).collect();
+		let rks = Backend::detect().key_expansion(&key);
+
+		// Random lengths so that both the full pipelines and the remainder are exercised
+		for _ in 0..20 {
+			let states: Vec<u128> = (0..rng.gen_range(0..100)).map(|_| rng.gen()).collect();
+
+			let expected: Vec<u128> = states.iter().map(|s| sisd::cipher(*s, &rks)).collect();
+
+			if vaes_512 {
+				let mut vaes_res = states.clone();
+				unsafe { vaes::cipher_blocks_512(&mut vaes_res, &rks) };
+				assert_eq!(vaes_res, expected, "[ERROR]: The 512-bit VAES implementation differs from the portable implementation");
+			}
+			if vaes_256 {
+				let mut vaes_res = states.clone();
+				unsafe { vaes::cipher_blocks_256(&mut vaes_res, &rks) };
+				assert_eq!(vaes_res, expected, "[ERROR]: The 256-bit VAES implementation differs from the portable implementation");
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+#[test]
+#[ignore = "benchmark - run with `cargo test --release -- --ignored --nocapture`"]
+fn bench_cipher_blocks() {
+	use std::time::{Duration, Instant};
+
+	const LEN: usize = 16 * 1024 * 1024;
+
+	// The best of a few runs, to keep other load on the machine out of the comparison
+	fn best_of(mut run: impl FnMut()) -> Duration {
+		(0..3).map(|_| {
+			let start = Instant::now();
+			run();
+			start.elapsed()
+		}).min().unwrap()
+	}
+
+	for backend in Backend::supported() {
+		let rks = backend.key_expansion(&[0x2b; 16]);
+		let mut states: Vec<u128> = (0..(LEN / 16) as u128).collect();
+
+		let one_block = best_of(|| for state in states.iter_mut() {
+			*state = backend.cipher(*state, &rks);
+		});
+		let batched = best_of(|| backend.cipher_blocks(&mut states, &rks));
+
+		println!("{}:", backend);
+		println!("  One block at a time: {:.0} MB/s", LEN as f64 / one_block.as_secs_f64() / 1e6);
+		println!("  Batched:             {:.0} MB/s", LEN as f64 / batched.as_secs_f64() / 1e6);
+
+		// The table-based backends have no batch kernel, but the others encrypt several blocks at once, which is the point of batching
+		if !matches!(backend, Backend::Sisd | Backend::Ttable) {
+			assert!(batched < one_block, "[ERROR]: Batched encryption is not faster than one block at a time for the {} backend", backend);
+		}
+	}
+}
+
+#[cfg(test)]
+#[test]
+#[ignore = "benchmark - run with `cargo test --release -- --ignored --nocapture`"]
+fn bench_software_backends() {
+	use std::time::Instant;
+
+	const LEN: usize = 16 * 1024 * 1024;
+
+	let rks = Backend::detect().key_expansion(&[0x2b; 16]);
+	let states: Vec<u128> = (0..(LEN / 16) as u128).collect();
+
+	let backends = [Backend::Sisd, Backend::Ttable, Backend::Bitsliced];
+	let mut throughput = [0.0; 3];
+
+	for (backend, throughput) in backends.into_iter().zip(throughput.iter_mut()) {
+		// The best of a few runs, to keep other load on the machine out of the comparison
+		for _ in 0..3 {
+			let mut states = states.clone();
+
+			let start = Instant::now();
+			backend.cipher_blocks(&mut states, &rks);
+			let elapsed = start.elapsed();
+
+			*throughput = f64::max(*throughput, LEN as f64 / elapsed.as_secs_f64() / 1e6);
+		}
+
+		println!("{:<10} {:.0} MB/s", backend.to_string(), throughput);
+	}
+
+	// The T-tables do a round in 16 lookups, where the table-based implementation works through the state a byte and a column at a time
+	assert!(throughput[1] > throughput[0], "[ERROR]: The T-table backend is not faster than the table-based one");
+}
+
 #[cfg(test)]
 #[test]
 fn test_aes_block_par() { // Also a test of the scoped_thread_pool - Although that is confirmed to work by it's own test
@@ -149,6 +797,17 @@ fn test_aes_block_par() { // Also a test of the scoped_thread_pool - Although th
 		assert_eq!(&input[..], &CIPHERTEXT[..]);
 	}
 
+	// Sequential, batched
+	{
+		let mut input = PLAINTEXT.to_vec();
+
+		let mut blocks = AesBlock::decompose(&mut input, &KEY, Some(IV));
+
+		AesBlock::encrypt_batch(&mut blocks);
+
+		assert_eq!(&input[..], &CIPHERTEXT[..]);
+	}
+
 	// Parallel (using scoped thread pool)
 	{
 		let mut input = PLAINTEXT.to_vec();
@@ -171,161 +830,853 @@ fn test_aes_block_par() { // Also a test of the scoped_thread_pool - Although th
 	}
 }
 
-/// This struct contains the information necessary to encrypt one block
+/// The byte order that keys and data are taken in by the AES/CTR functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ByteOrder {
+	/// The original layout of this crate: `key` is taken as a little-endian array of bytes, and `data` as a little-endian array of little-endian 16-byte blocks - Each key and block is byte-reversed compared to the standard layout, so this is not compatible with other AES implementations
+	#[default]
+	Legacy,
+	/// The standard FIPS-197/NIST SP 800-38A layout used by OpenSSL and other AES implementations: `key` and `data` are taken as byte strings, and the IV is the counter block read as a big-endian integer (so `iv.to_be_bytes()` is the IV as a byte string)
+	Standard
+}
+
+impl ByteOrder {
+	/// Converts `key` from this byte order into the FIPS-197 byte order
+	fn key_bytes(self, key: &[u8]) -> Zeroizing<u8> {
+		match self {
+			ByteOrder::Legacy => key_to_be_bytes(key),
+			ByteOrder::Standard => Zeroizing::from_slice(key)
+		}
+	}
+
+	/// Returns the function that converts an encrypted counter block into the byte order of the data
+	fn block_bytes(self) -> fn(u128) -> [u8; 16] {
+		match self {
+			ByteOrder::Legacy => u128::to_le_bytes,
+			ByteOrder::Standard => u128::to_be_bytes
+		}
+	}
+
+	/// Returns the function that converts a 16-byte block of data in this byte order into a 128-bit block
+	fn block_from_bytes(self) -> fn([u8; 16]) -> u128 {
+		match self {
+			ByteOrder::Legacy => u128::from_le_bytes,
+			ByteOrder::Standard => u128::from_be_bytes
+		}
+	}
+}
+
+/// Where the block counter sits in the 128-bit counter block, used by the `_with_layout` AES/CTR methods of `ExpandedKey`, `AesCtr` and `aes_encrypt_decrypt_at` - The other AES/CTR functions use `CounterLayout::FULL`
+///
+/// The counter is the `width` bits starting `offset` bits from the least significant bit of the counter block (which is the end of the counter block in `ByteOrder::Standard`), and the rest of the counter block is a nonce that stays fixed. The counter is incremented by one for each block, and the functions return `AesError::CounterOverflow` rather than let it wrap around and reuse keystream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterLayout {
+	width: u32,
+	offset: u32
+}
+
+impl CounterLayout {
+	/// The whole counter block is the counter
+	pub const FULL: CounterLayout = CounterLayout { width: 128, offset: 0 };
+	/// A 64-bit nonce followed by a 64-bit big-endian counter
+	pub const NONCE_64_COUNTER_64: CounterLayout = CounterLayout { width: 64, offset: 0 };
+	/// A 96-bit nonce followed by a 32-bit big-endian counter, as used by GCM and RFC 3686 (AES/CTR in IPsec)
+	pub const NONCE_96_COUNTER_32: CounterLayout = CounterLayout { width: 32, offset: 0 };
+
+	/// Creates a layout with a `width`-bit counter starting `offset` bits from the least significant bit of the counter block
+	/// # Panics
+	/// This function will panic if `width` is 0 or the counter does not fit in the counter block (`width + offset` is greater than 128)
+	pub fn new(width: u32, offset: u32) -> CounterLayout {
+		assert!(width > 0 && width.checked_add(offset).is_some_and(|end| end <= 128), "Invalid counter layout: {}-bit counter at offset {}", width, offset);
+
+		CounterLayout { width, offset }
+	}
+
+	/// The width of the counter in bits
+	pub fn width(&self) -> u32 {
+		self.width
+	}
+
+	/// The offset of the counter from the least significant bit of the counter block in bits
+	pub fn offset(&self) -> u32 {
+		self.offset
+	}
+
+	/// The largest value of the counter
+	fn max(self) -> u128 {
+		u128::MAX >> (128 - self.width)
+	}
+
+	/// Checks that `num_blks` blocks can be encrypted starting from counter block `iv` without the counter wrapping
+	fn check(self, iv: u128, num_blks: u128) -> Result<(), AesError> {
+		let ctr = (iv >> self.offset) & self.max();
+
+		if num_blks == 0 || (num_blks - 1) <= self.max() - ctr {
+			Ok(())
+		} else {
+			Err(AesError::CounterOverflow)
+		}
+	}
+
+	/// Returns counter block `iv` with the counter advanced by `n` - `check` must have been called first
+	fn block(self, iv: u128, n: u128) -> u128 {
+		let mask = self.max() << self.offset;
+		(iv & !mask) | (iv & mask).wrapping_add(n << self.offset)
+	}
+
+	/// Generates a random IV with the counter set to 0, so that the most blocks can be encrypted with it - If the whole counter block is the counter, the whole IV is random
+	fn random_iv(self) -> Result<u128, AesError> {
+		if self.width == 128 {
+			random_iv()
+		} else {
+			Ok(random_iv()? & !(self.max() << self.offset))
+		}
+	}
+}
+
+/// The error returned by the `Result`-returning functions of this module and its submodules - The `try_` variants of the functions that otherwise panic return it instead of panicking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AesError {
+	/// The key is not 128, 192 or 256-bit - Holds the length of the key in bytes
+	InvalidKeyLength(usize),
+	/// An IV was to be generated, but an RNG providing secure entropy could not be found/used by the `getrandom` crate
+	EntropyFailure,
+	/// The data is too long for the CTR counter width and starting counter, so that the counter would wrap around and reuse keystream
+	CounterOverflow,
+	/// The GCM authentication tag does not match the ciphertext and associated data
+	AuthenticationFailure,
+	/// Decrypted CBC data does not end in valid PKCS#7 padding
+	InvalidPadding,
+	/// The data is not a valid length for the mode (not a whole number of blocks for CBC without padding, or longer than 2^36 - 32 bytes for GCM) - Holds the length of the data in bytes
+	InvalidDataLength(usize),
+	/// The GCM IV is empty
+	EmptyIv,
+	/// A backend was asked for that the CPU does not support (or that is not built for this architecture)
+	UnsupportedBackend(Backend)
+}
+
+impl fmt::Display for AesError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			AesError::InvalidKeyLength(len) => write!(f, "invalid AES key length: {} bytes - the key must be 16, 24 or 32 bytes", len),
+			AesError::EntropyFailure => write!(f, "could not generate an IV - no RNG providing secure entropy is available"),
+			AesError::CounterOverflow => write!(f, "the CTR block counter would wrap around - the data is too long for the counter width and starting counter"),
+			AesError::AuthenticationFailure => write!(f, "{}", gcm::AuthenticationError),
+			AesError::InvalidPadding => write!(f, "{}", cbc::PaddingError),
+			AesError::InvalidDataLength(len) => write!(f, "invalid data length for the mode: {} bytes", len),
+			AesError::EmptyIv => write!(f, "the IV must not be empty"),
+			AesError::UnsupportedBackend(backend) => write!(f, "the {} AES backend is not supported by this CPU", backend)
+		}
+	}
+}
+
+impl Error for AesError {}
+
+impl From<cbc::PaddingError> for AesError {
+	fn from(_: cbc::PaddingError) -> AesError {
+		AesError::InvalidPadding
+	}
+}
+
+impl From<gcm::AuthenticationError> for AesError {
+	fn from(_: gcm::AuthenticationError) -> AesError {
+		AesError::AuthenticationFailure
+	}
+}
+
+/// Checks that `key` is 128, 192 or 256-bit (16, 24 or 32 bytes)
+fn check_key_len(key: &[u8]) -> Result<(), AesError> {
+	match key.len() {
+		16 | 24 | 32 => Ok(()),
+		len => Err(AesError::InvalidKeyLength(len))
+	}
+}
+
+/// Unwraps the result of a `try_` function for the function that panics instead
+fn unwrap_or_panic<T>(res: Result<T, AesError>) -> T {
+	res.unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// This struct contains the information necessary to encrypt one 16-byte block of data independently of the others
+///
+/// Created by `AesBlock::decompose`. Each block is `Send`, so the blocks can be handed to different threads (for example as tasks in a `ThreadPool`) and encrypted in any order
 pub struct AesBlock<'a> {
 	ctr_block: u128,
 	data: &'a mut [u8],
-	round_keys: Arc<[u128; 11]>
+	round_keys: Arc<RoundKeys>,
+	backend: Backend,
+	order: ByteOrder
 }
 
 impl<'a> AesBlock<'a> {
-	/// Creates a Vec of
-	fn decompose(data: &'a mut [u8], key: &[u8], iv: Option<u128>) -> Vec<AesBlock<'a>> {
-		assert_eq!(key.len(), 16);
-
-		// Initialisation Vector (initial counter)
-		// If provided, then we use that, if not provided, then we generate one
-		let iv = match iv {
-			Some(iv_provided) => iv_provided,
-			None => {
-				let mut rng = ChaCha20Rng::from_entropy(); // Seed the ChaCha20Rng CSPRNG using a non-deterministic seed, panic if can't
-				let mut iv = [0u8; 16];
-				rng.fill_bytes(&mut iv);
-				u128::from_ne_bytes(iv) // Can just use from native endianness cause we aren't reading it from input
+	/// Creates a Vec of AesBlocks, one for each 16-byte block of `data` (the last may be shorter), that together perform AES/CTR encryption/decryption on `data` using slice `key` (`key` having gone through necessary key derivation and being exactly 128, 192 or 256-bit) and an IV if provided, as `aes_encrypt_decrypt` does
+	///
+	/// The key is expanded once and the round keys are shared between the blocks. If `iv` is not provided then one is generated, and it can be retrieved from the first block with `AesBlock::counter`
+	///
+	/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks (`ByteOrder::Legacy`)
+	/// # Panics
+	/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), the counter would wrap around past `u128::MAX` or, if `iv` is not provided, an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `AesBlock::try_decompose` to get an `AesError` instead
+	pub fn decompose(data: &'a mut [u8], key: &[u8], iv: Option<u128>) -> Vec<AesBlock<'a>> {
+		unwrap_or_panic(Self::try_decompose(data, key, iv))
+	}
+
+	/// The same as `AesBlock::decompose`, but returns `AesError::InvalidKeyLength`, `AesError::CounterOverflow` or `AesError::EntropyFailure` instead of panicking
+	pub fn try_decompose(data: &'a mut [u8], key: &[u8], iv: Option<u128>) -> Result<Vec<AesBlock<'a>>, AesError> {
+		Self::try_decompose_with_order(data, key, iv, ByteOrder::Legacy)
+	}
+
+	/// The same as `AesBlock::decompose`, but with `key` and `data` taken in byte order `order`
+	/// # Panics
+	/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), the counter would wrap around past `u128::MAX` or, if `iv` is not provided, an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `AesBlock::try_decompose_with_order` to get an `AesError` instead
+	pub fn decompose_with_order(data: &'a mut [u8], key: &[u8], iv: Option<u128>, order: ByteOrder) -> Vec<AesBlock<'a>> {
+		unwrap_or_panic(Self::try_decompose_with_order(data, key, iv, order))
+	}
+
+	/// The same as `AesBlock::decompose_with_order`, but returns `AesError::InvalidKeyLength`, `AesError::CounterOverflow` or `AesError::EntropyFailure` instead of panicking
+	pub fn try_decompose_with_order(data: &'a mut [u8], key: &[u8], iv: Option<u128>, order: ByteOrder) -> Result<Vec<AesBlock<'a>>, AesError> {
+		ExpandedKey::try_new(key, order)?.decompose(data, iv)
+	}
+
+	/// Creates an AesBlock that encrypts `data` with counter block `ctr_block` and `key`, sharing its round keys
+	fn new(key: &ExpandedKey, ctr_block: u128, data: &'a mut [u8]) -> AesBlock<'a> {
+		AesBlock { ctr_block, data, round_keys: key.round_keys.clone(), backend: key.backend, order: key.order }
+	}
+
+	/// Encrypts (or decrypts - they are the same operation) the block in-place
+	pub fn encrypt(&mut self) {
+		// Now for the actual encryption
+		let enc_counter = self.order.block_bytes()(self.backend.cipher(self.ctr_block, &self.round_keys));
+		for (b, k) in self.data.iter_mut().zip(enc_counter) {
+			*b ^= k;
+		}
+	}
+
+	/// Encrypts (or decrypts) each block in `blocks` in-place, the same as calling `AesBlock::encrypt` on each but faster, as consecutive blocks that share round keys (blocks from the same `AesBlock::decompose` call) are encrypted several at a time
+	pub fn encrypt_batch(blocks: &mut [AesBlock<'a>]) {
+		for batch in blocks.chunks_mut(BATCH_SIZE) {
+			if !batch.iter().all(|b| Arc::ptr_eq(&b.round_keys, &batch[0].round_keys)) {
+				batch.iter_mut().for_each(|b| b.encrypt());
+				continue;
 			}
-		};
 
-		let key = u128::from_le_bytes(key.try_into().unwrap());
+			let mut enc_counters = [0u128; BATCH_SIZE];
+			for (enc_counter, b) in enc_counters.iter_mut().zip(batch.iter()) {
+				*enc_counter = b.ctr_block;
+			}
 
-		let round_keys = Arc::new(key_expansion(key));
+			batch[0].backend.cipher_blocks(&mut enc_counters[..batch.len()], &batch[0].round_keys);
 
-		let num_128_blks = (data.len() as f64 / 16.0).ceil() as usize;
+			for (b, enc_counter) in batch.iter_mut().zip(enc_counters) {
+				for (d, k) in b.data.iter_mut().zip(b.order.block_bytes()(enc_counter)) {
+					*d ^= k;
+				}
+			}
+		}
+	}
 
-		// Create counter iterator with num_128_blks elements adding iv onto each one to turn it into a range from iv to num_128_blocks + iv
-		// Didn't directly create a range (iv..num_128_blks + iv) cause what if num_128_blks + iv overflows? The range becomes invalid. Using map makes sure that things keep going in the case of overflows
-		let counter = (0..num_128_blks).map(|n| (n as u128) + iv);
+	/// Returns the counter block that this block is encrypted with - For the first block returned by `AesBlock::decompose` this is the IV
+	pub fn counter(&self) -> u128 {
+		self.ctr_block
+	}
+}
 
-		// Get a mutable iterator to each 128-bit block of data to be encrypted, and combine that with the counter iterator above, to make a single iterator of pairs (data, counter)
-		// For each element, create an AesBlock out of the element and an Arc to round keys
-		// Then collect it all into a Vec
-		data.chunks_mut(16).zip(counter).map(|(data_chunk, counter)| {
-			AesBlock { ctr_block: counter, data: data_chunk, round_keys: round_keys.clone() }
-		}).collect()
+/// A stream cipher state for AES/CTR, for encrypting or decrypting a message that arrives in pieces
+///
+/// Each call to `AesCtr::apply_keystream` continues from where the last one stopped, so the output is the same however the message is split up, and is identical to `ExpandedKey::encrypt_decrypt_with_layout` on the whole message. The key is expanded once, when the `AesCtr` is created, or an `ExpandedKey` can be shared between many with `ExpandedKey::ctr`
+///
+/// `AesCtr` is deliberately not `Clone`, as two copies used on different data would reuse the keystream
+pub struct AesCtr {
+	key: ExpandedKey,
+	iv: u128,
+	layout: CounterLayout,
+	pos: u64,
+	/// The number and keystream of the last block that was only partly used, so that data arriving in pieces shorter than a block doesn't encrypt the same counter block again for each piece
+	partial: Option<(u64, [u8; 16])>
+}
+
+impl AesCtr {
+	/// Creates an `AesCtr` at the start of a message encrypted with slice `key` (`key` having gone through necessary key derivation and being exactly 128, 192 or 256-bit) and `iv`, with the counter laid out according to `layout`, and `key` and data taken in byte order `order`
+	/// # Panics
+	/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) - Use `AesCtr::try_new` to get an `AesError` instead
+	pub fn new(key: &[u8], iv: u128, layout: CounterLayout, order: ByteOrder) -> AesCtr {
+		unwrap_or_panic(AesCtr::try_new(key, iv, layout, order))
 	}
 
-	fn encrypt(&mut self) {
-		// Now for the actual encryption
-		let enc_counter = cipher(self.ctr_block, &*self.round_keys).to_le_bytes();
-		let block = &mut self.data;
-		for i in 0..block.len() {
-			block[i] ^= enc_counter[i];
+	/// The same as `AesCtr::new`, but returns `AesError::InvalidKeyLength` instead of panicking
+	pub fn try_new(key: &[u8], iv: u128, layout: CounterLayout, order: ByteOrder) -> Result<AesCtr, AesError> {
+		Ok(ExpandedKey::try_new(key, order)?.ctr(iv, layout))
+	}
+
+	/// Creates an `AesCtr` at the start of a message encrypted with `key` and `iv`
+	fn from_key(key: ExpandedKey, iv: u128, layout: CounterLayout) -> AesCtr {
+		AesCtr { key, iv, layout, pos: 0, partial: None }
+	}
+
+	/// Creates an `AesCtr` at the start of a new message with a random IV, which can be retrieved with `AesCtr::iv` - The counter in the IV is set to 0, unless the whole counter block is the counter
+	/// # Panics
+	/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) or an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `AesCtr::try_with_random_iv` to get an `AesError` instead
+	pub fn with_random_iv(key: &[u8], layout: CounterLayout, order: ByteOrder) -> AesCtr {
+		unwrap_or_panic(AesCtr::try_with_random_iv(key, layout, order))
+	}
+
+	/// The same as `AesCtr::with_random_iv`, but returns `AesError::InvalidKeyLength` or `AesError::EntropyFailure` instead of panicking
+	pub fn try_with_random_iv(key: &[u8], layout: CounterLayout, order: ByteOrder) -> Result<AesCtr, AesError> {
+		ExpandedKey::try_new(key, order)?.ctr_with_random_iv(layout)
+	}
+
+	/// Encrypts (or decrypts - they are the same operation) `data` in-place as the next part of the message
+	///
+	/// Returns `AesError::CounterOverflow` without modifying `data` or advancing the position if the message has become too long for the counter
+	pub fn apply_keystream(&mut self, data: &mut [u8]) -> Result<(), AesError> {
+		let (iv, layout) = (self.iv, self.layout);
+		self.apply_with(data, |key, whole, first_blk| {
+			let counter = (0..whole.len() / 16).map(|n| layout.block(iv, first_blk + n as u128));
+			apply_keystream(whole, key, counter, key.order.block_bytes());
+		})
+	}
+
+	/// The same as `AesCtr::apply_keystream`, but encrypts `data` in parallel using the threads of `pool`, which is worthwhile for large pieces of data
+	pub fn apply_keystream_par(&mut self, data: &mut [u8], pool: &mut ThreadPool) -> Result<(), AesError> {
+		let (iv, layout) = (self.iv, self.layout);
+		self.apply_with(data, |key, whole, first_blk| {
+			apply_keystream_par(whole, key, |n| layout.block(iv, first_blk + n), key.order.block_bytes(), pool);
+		})
+	}
+
+	/// Encrypts `data` as the next part of the message, with `whole_blocks` encrypting the whole blocks in the middle given the number of the first - The partial blocks at either end use the cached keystream of their block where possible
+	fn apply_with(&mut self, data: &mut [u8], whole_blocks: impl FnOnce(&ExpandedKey, &mut [u8], u128)) -> Result<(), AesError> {
+		let end = self.end_of(data)?;
+		self.layout.check(self.iv, (end as u128).div_ceil(16))?;
+
+		// Finish the current block, so that the rest starts on a block boundary
+		let skip = (self.pos % 16) as usize;
+		let head_len = if skip == 0 { 0 } else { (16 - skip).min(data.len()) };
+		let (head, rest) = data.split_at_mut(head_len);
+		if !head.is_empty() {
+			let keystream = self.keystream_block(self.pos / 16);
+			for (b, k) in head.iter_mut().zip(&keystream[skip..]) {
+				*b ^= k;
+			}
+		}
+
+		let (whole, tail) = rest.split_at_mut(rest.len() / 16 * 16);
+		whole_blocks(&self.key, whole, ((self.pos + head_len as u64) / 16) as u128);
+
+		// Start the block the data ends in, keeping its keystream for the next call
+		if !tail.is_empty() {
+			let keystream = self.keystream_block(end / 16);
+			for (b, k) in tail.iter_mut().zip(&keystream) {
+				*b ^= k;
+			}
 		}
+
+		self.pos = end;
+
+		Ok(())
+	}
+
+	/// Returns the keystream of block `blk` of the message, from the cache if it is the cached block, or computing and caching it otherwise - The counter must have been checked to reach `blk`
+	fn keystream_block(&mut self, blk: u64) -> [u8; 16] {
+		match self.partial {
+			Some((cached, keystream)) if cached == blk => keystream,
+			_ => {
+				let keystream = self.key.order.block_bytes()(self.key.cipher(self.layout.block(self.iv, blk as u128)));
+				self.partial = Some((blk, keystream));
+				keystream
+			}
+		}
+	}
+
+	/// Returns the position after `data` is processed - Returns `AesError::CounterOverflow` if it is past the end of what the position can hold, as the counter can be wider than the position
+	fn end_of(&self, data: &[u8]) -> Result<u64, AesError> {
+		self.pos.checked_add(data.len() as u64).ok_or(AesError::CounterOverflow)
+	}
+
+	/// Returns the number of bytes from the position to the end of the keystream, which is where the counter would wrap or the position would overflow
+	fn remaining(&self) -> u64 {
+		let ctr = (self.iv >> self.layout.offset) & self.layout.max();
+		let end = (self.layout.max() - ctr).saturating_add(1).saturating_mul(16).min(u64::MAX as u128) as u64;
+		end.saturating_sub(self.pos)
+	}
+
+	/// Returns the key of the message
+	pub fn key(&self) -> &ExpandedKey {
+		&self.key
+	}
+
+	/// Returns the IV of the message
+	pub fn iv(&self) -> u128 {
+		self.iv
+	}
+
+	/// Returns the position in the message in bytes, which is where the next call to `AesCtr::apply_keystream` will continue from
+	pub fn position(&self) -> u64 {
+		self.pos
+	}
+
+	/// Moves to byte `pos` of the message, so that the next call to `AesCtr::apply_keystream` continues from there
+	pub fn seek(&mut self, pos: u64) {
+		self.pos = pos;
 	}
 }
 
-/// Perform AES-128/CTR encryption on slice `data` using slice `key` (`key` having gone through necessary key derivation and being exactly 128-bit)
+impl Drop for AesCtr {
+	fn drop(&mut self) {
+		if let Some((_, keystream)) = &mut self.partial {
+			zeroize::zeroize(keystream);
+		}
+	}
+}
+
+/// Perform AES/CTR encryption on slice `data` using slice `key` (`key` having gone through necessary key derivation and being exactly 128, 192 or 256-bit, which selects AES-128, AES-192 or AES-256)
 ///
 /// Will use x86/x86_64 AES-NI intrinsics if available
 ///
 /// Returns the IV that needs to be stored alongside the encrypted data and used for decryption. The data is encrypted in-place
 ///
-/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks
+/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks (`ByteOrder::Legacy`)
 /// # Panics
-/// This function will panic if `key` is not 128-bit/16-byte or an RNG providing secure entropy could not be found/used by the `getrandom` crate
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) or an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `try_aes_encrypt` to get an `AesError` instead
 pub fn aes_encrypt(data: &mut [u8], key: &[u8]) -> u128 {
-	aes_encrypt_decrypt(data, key, None)
+	unwrap_or_panic(try_aes_encrypt(data, key))
+}
+
+/// The same as `aes_encrypt`, but returns `AesError::InvalidKeyLength`, `AesError::CounterOverflow` or `AesError::EntropyFailure` instead of panicking, without modifying `data`
+pub fn try_aes_encrypt(data: &mut [u8], key: &[u8]) -> Result<u128, AesError> {
+	try_aes_encrypt_decrypt(data, key, None)
 }
 
-/// Perform AES-128/CTR decryption on slice `data` using slice `key` (`key` having gone through necessary key derivation and being exactly 128-bit) and 128-bit `iv` - The IV that was used for encryption
+/// Perform AES/CTR decryption on slice `data` using slice `key` (`key` having gone through necessary key derivation and being exactly 128, 192 or 256-bit) and 128-bit `iv` - The IV that was used for encryption
 ///
 /// Will use x86/x86_64 AES-NI intrinsics if available
 ///
 /// The data is decrypted in-place
 ///
-/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks
+/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks (`ByteOrder::Legacy`)
 /// # Panics
-/// This function will panic if `key` is not 128-bit/16-byte
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) or the counter would wrap around past `u128::MAX` - Use `try_aes_decrypt` to get an `AesError` instead
 pub fn aes_decrypt(data: &mut [u8], key: &[u8], iv: u128) {
-	aes_encrypt_decrypt(data, key, Some(iv));
+	unwrap_or_panic(try_aes_decrypt(data, key, iv));
+}
+
+/// The same as `aes_decrypt`, but returns `AesError::InvalidKeyLength` or `AesError::CounterOverflow` instead of panicking, without modifying `data`
+pub fn try_aes_decrypt(data: &mut [u8], key: &[u8], iv: u128) -> Result<(), AesError> {
+	try_aes_encrypt_decrypt(data, key, Some(iv)).map(|_| ())
 }
 
-/// Perform AES-128/CTR encryption/decryption (both are the same operation) on slice `data` using slice `key` (`key` having gone through necessary key derivation and being exactly 128-bit) and an IV if provided. When performing decryption you need to provide the IV that was used for encryption in order for the decryption to be correct
+/// Perform AES/CTR encryption/decryption (both are the same operation) on slice `data` using slice `key` (`key` having gone through necessary key derivation and being exactly 128, 192 or 256-bit) and an IV if provided. When performing decryption you need to provide the IV that was used for encryption in order for the decryption to be correct
 ///
 /// Will use x86/x86_64 AES-NI intrinsics if available
 ///
+/// The whole 128-bit counter block is the counter (`CounterLayout::FULL`) - Use `ExpandedKey::encrypt_decrypt_with_layout` to pick a different `CounterLayout`
+///
 /// Returns the IV that needs to be stored alongside the encrypted data and used for decryption. The data is encrypted in-place
 ///
-/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks
+/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks (`ByteOrder::Legacy`)
 /// # Panics
-/// This function will panic if `key` is not 128-bit/16-byte or, if `iv` is not provided, an RNG providing secure entropy could not be found/used by the `getrandom` crate
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), the counter would wrap around past `u128::MAX` or, if `iv` is not provided, an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `try_aes_encrypt_decrypt` to get an `AesError` instead
 pub fn aes_encrypt_decrypt(data: &mut [u8], key: &[u8], iv: Option<u128>) -> u128 {
-	assert_eq!(key.len(), 16);
-
-	// Initialisation Vector (initial counter)
-	// If provided, then we use that, if not provided, then we generate one
-	let iv = match iv {
-		Some(iv_provided) => iv_provided,
-		None => {
-			let mut rng = ChaCha20Rng::from_entropy(); // Seed the ChaCha20Rng CSPRNG using a non-deterministic seed, panic if can't
-			let mut iv = [0u8; 16];
-			rng.fill_bytes(&mut iv);
-			u128::from_ne_bytes(iv) // Can just use from native endianness cause we aren't reading it from input
-		}
-	};
+	unwrap_or_panic(try_aes_encrypt_decrypt(data, key, iv))
+}
+
+/// The same as `aes_encrypt_decrypt`, but returns `AesError::InvalidKeyLength`, `AesError::CounterOverflow` or `AesError::EntropyFailure` instead of panicking, without modifying `data`
+pub fn try_aes_encrypt_decrypt(data: &mut [u8], key: &[u8], iv: Option<u128>) -> Result<u128, AesError> {
+	try_aes_encrypt_decrypt_with_order(data, key, iv, ByteOrder::Legacy)
+}
 
-	let key = u128::from_le_bytes(key.try_into().unwrap());
+/// The same as `aes_encrypt_decrypt`, but with `key` and `data` taken in byte order `order` - Use `ByteOrder::Standard` for output compatible with other AES implementations
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), the counter would wrap around past `u128::MAX` or, if `iv` is not provided, an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `try_aes_encrypt_decrypt_with_order` to get an `AesError` instead
+pub fn aes_encrypt_decrypt_with_order(data: &mut [u8], key: &[u8], iv: Option<u128>, order: ByteOrder) -> u128 {
+	unwrap_or_panic(try_aes_encrypt_decrypt_with_order(data, key, iv, order))
+}
+
+/// The same as `aes_encrypt_decrypt_with_order`, but returns `AesError::InvalidKeyLength`, `AesError::CounterOverflow` or `AesError::EntropyFailure` instead of panicking, without modifying `data`
+pub fn try_aes_encrypt_decrypt_with_order(data: &mut [u8], key: &[u8], iv: Option<u128>, order: ByteOrder) -> Result<u128, AesError> {
+	ExpandedKey::try_new(key, order)?.encrypt_decrypt(data, iv)
+}
+
+/// Perform AES/CTR encryption/decryption on slice `data` in parallel using the threads of `pool`. Otherwise the same as `aes_encrypt_decrypt`, and the output is identical
+///
+/// `data` is split into one large contiguous chunk per worker thread, with each chunk's counter starting at the IV plus the index of its first block
+///
+/// Returns the IV that needs to be stored alongside the encrypted data and used for decryption. The data is encrypted in-place
+///
+/// `key` is taken as a little-endian array of bytes; `data` is taken as a little-endian array of little-endian 16-byte blocks (`ByteOrder::Legacy`)
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), the counter would wrap around past `u128::MAX` or, if `iv` is not provided, an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `try_aes_encrypt_decrypt_par` to get an `AesError` instead
+pub fn aes_encrypt_decrypt_par(data: &mut [u8], key: &[u8], iv: Option<u128>, pool: &mut ThreadPool) -> u128 {
+	unwrap_or_panic(try_aes_encrypt_decrypt_par(data, key, iv, pool))
+}
+
+/// The same as `aes_encrypt_decrypt_par`, but returns `AesError::InvalidKeyLength`, `AesError::CounterOverflow` or `AesError::EntropyFailure` instead of panicking, without modifying `data`
+pub fn try_aes_encrypt_decrypt_par(data: &mut [u8], key: &[u8], iv: Option<u128>, pool: &mut ThreadPool) -> Result<u128, AesError> {
+	try_aes_encrypt_decrypt_par_with_order(data, key, iv, ByteOrder::Legacy, pool)
+}
+
+/// The same as `aes_encrypt_decrypt_par`, but with `key` and `data` taken in byte order `order` - Use `ByteOrder::Standard` for output compatible with other AES implementations
+/// # Panics
+/// This function will panic if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), the counter would wrap around past `u128::MAX` or, if `iv` is not provided, an RNG providing secure entropy could not be found/used by the `getrandom` crate - Use `try_aes_encrypt_decrypt_par_with_order` to get an `AesError` instead
+pub fn aes_encrypt_decrypt_par_with_order(data: &mut [u8], key: &[u8], iv: Option<u128>, order: ByteOrder, pool: &mut ThreadPool) -> u128 {
+	unwrap_or_panic(try_aes_encrypt_decrypt_par_with_order(data, key, iv, order, pool))
+}
+
+/// The same as `aes_encrypt_decrypt_par_with_order`, but returns `AesError::InvalidKeyLength`, `AesError::CounterOverflow` or `AesError::EntropyFailure` instead of panicking, without modifying `data`
+pub fn try_aes_encrypt_decrypt_par_with_order(data: &mut [u8], key: &[u8], iv: Option<u128>, order: ByteOrder, pool: &mut ThreadPool) -> Result<u128, AesError> {
+	ExpandedKey::try_new(key, order)?.encrypt_decrypt_par(data, iv, pool)
+}
 
-	let rks = key_expansion(key);
+/// Generates a random 128-bit IV using the ChaCha20Rng CSPRNG - Returns `AesError::EntropyFailure` if an RNG providing secure entropy could not be found/used by the `getrandom` crate
+fn random_iv() -> Result<u128, AesError> {
+	let mut rng = ChaCha20Rng::from_rng(OsRng).map_err(|_| AesError::EntropyFailure)?; // Seed the ChaCha20Rng CSPRNG using a non-deterministic seed from the OS
+	let mut iv = [0u8; 16];
+	rng.fill_bytes(&mut iv);
+	Ok(u128::from_ne_bytes(iv)) // Can just use from native endianness cause we aren't reading it from input
+}
 
-	let num_128_blks = (data.len() as f64 / 16.0).ceil() as usize;
+/// The number of counter blocks `apply_keystream` and `AesBlock::encrypt_batch` encrypt at once with `cipher_blocks` - Large enough to fill the widest pipeline
+const BATCH_SIZE: usize = 32;
 
-	// Create counter with num_128_blks elements adding iv onto each one to turn it into a range from iv to num_128_blocks + iv
-	// Didn't directly create a range (iv..num_128_blks + iv) cause what if num_128_blks + iv overflows? The range becomes invalid. Using map makes sure that things keep going in the case of overflows
-	let counter: Vec<u128> = (0..num_128_blks).map(|n| (n as u128) + iv).collect();
+/// XORs each 16-byte chunk of `data` (the last may be shorter) with the encryption of the corresponding counter block from `counter`, with `to_bytes` converting each encrypted counter block into the byte order of `data`
+///
+/// The counter blocks are encrypted in batches with `cipher_blocks`
+fn apply_keystream(data: &mut [u8], key: &ExpandedKey, mut counter: impl Iterator<Item = u128>, to_bytes: fn(u128) -> [u8; 16]) {
+	for batch in data.chunks_mut(BATCH_SIZE * 16) {
+		let mut enc_counters = [0u128; BATCH_SIZE];
+		let num_blks = batch.len().div_ceil(16);
+		for (enc_counter, ctr) in enc_counters[..num_blks].iter_mut().zip(&mut counter) {
+			*enc_counter = ctr;
+		}
 
-	// Get a mutable iterator to each 128-bit block of data to be encrypted
-	let mut data_chunks: Vec<&mut [u8]> = data.chunks_mut(16).collect();
+		key.cipher_blocks(&mut enc_counters[..num_blks]);
 
-	// Now for the actual encryption
-	for i in 0..counter.len() {
-		let enc_counter = cipher(counter[i], &rks).to_le_bytes();
-		let block = &mut data_chunks[i];
-		for i in 0..block.len() {
-			block[i] ^= enc_counter[i];
+		for (block, enc_counter) in batch.chunks_mut(16).zip(enc_counters) {
+			for (b, k) in block.iter_mut().zip(to_bytes(enc_counter)) {
+				*b ^= k;
+			}
 		}
 	}
-
-	iv
 }
 
-/// Expands one 128-bit key into 11 128-bit round keys
+/// Perform AES/CTR encryption/decryption on slice `data`, which is the part of a larger message starting at byte `offset`, giving the same result as the corresponding part of `ExpandedKey::encrypt_decrypt_with_layout` on the whole message - This allows any range of a message to be decrypted (or re-encrypted) without processing the data before it
 ///
-/// Will use x86/x86_64 AES-NI intrinsics if available
-fn key_expansion(key: u128) -> [u128; 11] {
-	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-	{
-		if is_x86_feature_detected!("aes") && is_x86_feature_detected!("sse2") {
-			return unsafe { simd::key_expansion(key) };
+/// `offset` does not need to be a multiple of 16: The keystream of a partial first block is taken from the middle of the block, and the last block may also be partial. The counter is laid out according to `layout`, and `iv` is the IV that was used for the whole message
+///
+/// Returns `AesError::CounterOverflow` if the end of `data` is past the last block the counter can count from the IV's counter, or `AesError::InvalidKeyLength` if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes) - `data` is not modified if an error is returned
+pub fn aes_encrypt_decrypt_at(data: &mut [u8], key: &[u8], iv: u128, offset: u64, layout: CounterLayout, order: ByteOrder) -> Result<(), AesError> {
+	ExpandedKey::try_new(key, order)?.encrypt_decrypt_at(data, iv, offset, layout)
+}
+
+/// XORs `data`, which starts at byte `offset` of the message, with the corresponding part of the keystream of counter blocks laid out according to `layout` starting from `iv` - Returns `AesError::CounterOverflow` without modifying `data` if the counter would wrap
+fn apply_keystream_at(data: &mut [u8], key: &ExpandedKey, iv: u128, offset: u64, layout: CounterLayout, to_bytes: fn(u128) -> [u8; 16]) -> Result<(), AesError> {
+	let first_blk = (offset / 16) as u128;
+	let skip = (offset % 16) as usize;
+
+	// Every block up to the end of data must be countable, including the ones before data
+	layout.check(iv, (offset as u128 + data.len() as u128).div_ceil(16))?;
+
+	// The bytes up to the next block boundary, which use the end of the first block's keystream
+	let head_len = if skip == 0 { 0 } else { (16 - skip).min(data.len()) };
+	let (head, tail) = data.split_at_mut(head_len);
+
+	if !head.is_empty() {
+		let keystream = to_bytes(key.cipher(layout.block(iv, first_blk)));
+		for (b, k) in head.iter_mut().zip(&keystream[skip..]) {
+			*b ^= k;
 		}
 	}
 
-	sisd::key_expansion(key)
+	let tail_first_blk = if skip == 0 { first_blk } else { first_blk + 1 };
+	let counter = (0..tail.len().div_ceil(16)).map(|n| layout.block(iv, tail_first_blk + n as u128));
+	apply_keystream(tail, key, counter, to_bytes);
+
+	Ok(())
 }
 
-/// Performs the cipher on a 128-bit state with 11 128-bit round keys
+/// Performs `apply_keystream` on `data` in parallel using the threads of `pool`, with `counter_block` giving the counter block for the block at each index of `data`
 ///
-/// Will use x86/x86_64 AES-NI intrinsics if available
-/// # Panics
-/// This function panics if `round_keys` length is not equal to 11
-fn cipher(state: u128, round_keys: &[u128]) -> u128 {
-	assert_eq!(round_keys.len(), 11);
+/// `data` is split into one large contiguous chunk per worker thread, so that each thread encrypts in batches
+fn apply_keystream_par(data: &mut [u8], key: &ExpandedKey, counter_block: impl Fn(u128) -> u128 + Sync, to_bytes: fn(u128) -> [u8; 16], pool: &mut ThreadPool) {
+	let counter_block = &counter_block;
+
+	pool.scoped(|scope| {
+		scope.par_chunks_mut(data, 16, move |first_blk, chunk| {
+			// Same counter as the sequential version, offset by the number of blocks before this chunk
+			let counter = (0..chunk.len().div_ceil(16)).map(|n| counter_block((first_blk + n) as u128));
+			apply_keystream(chunk, key, counter, to_bytes);
+		});
+	});
+}
+
+/// Converts a key taken as a little-endian array of bytes into the FIPS-197 byte order (most significant byte first)
+fn key_to_be_bytes(key: &[u8]) -> Zeroizing<u8> {
+	let mut bytes = Zeroizing::from_slice(key);
+	bytes.reverse();
+	bytes
+}
+
+/// The 11, 13 or 15 round keys of an AES-128, AES-192 or AES-256 key, in a fixed-size array large enough for AES-256 so that the key schedule is never resized or reallocated - Dereferences to the round keys in use, and is zeroed when dropped
+pub(super) struct RoundKeys {
+	keys: [u128; 15],
+	len: usize
+}
+
+impl RoundKeys {
+	/// Copies the round keys in `keys`
+	/// # Panics
+	/// This function panics if `keys` length is not equal to 11, 13 or 15
+	fn from_slice(keys: &[u128]) -> RoundKeys {
+		assert!(matches!(keys.len(), 11 | 13 | 15));
+
+		let mut rks = RoundKeys { keys: [0; 15], len: keys.len() };
+		rks.keys[..keys.len()].copy_from_slice(keys);
+		rks
+	}
+
+	/// Moves the round keys in array `keys` into `RoundKeys`, zeroing the array
+	fn from_array<const N: usize>(mut keys: [u128; N]) -> RoundKeys {
+		let rks = RoundKeys::from_slice(&keys);
+		zeroize::zeroize(&mut keys);
+		rks
+	}
 
+	/// Moves the round keys in `keys` into `RoundKeys`, zeroing `keys` before it is freed
+	fn from_vec(mut keys: Vec<u128>) -> RoundKeys {
+		let rks = RoundKeys::from_slice(&keys);
+		zeroize::zeroize(&mut keys);
+		rks
+	}
+}
+
+impl Deref for RoundKeys {
+	type Target = [u128];
+
+	fn deref(&self) -> &[u128] {
+		&self.keys[..self.len]
+	}
+}
+
+impl Drop for RoundKeys {
+	fn drop(&mut self) {
+		zeroize::zeroize(&mut self.keys);
+	}
+}
+
+/// An AES implementation - By default the fastest one the CPU supports is picked at runtime, `Backend::set_default` picks one for the whole process, and `ExpandedKey::with_backend` picks one for a single key, for example to test or benchmark one implementation
+///
+/// `ExpandedKey` holds its backend, so the CPU features are not checked again for each block. The free functions use `Backend::current`, which checks the CPU features once and caches the result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Backend {
+	/// The portable table-based implementation - It looks up tables indexed by secret data, so it is not constant-time
+	Sisd,
+	/// The portable T-table implementation, several times faster than `Sisd` but not constant-time either
+	Ttable,
+	/// The portable bitsliced implementation, which is constant-time - It encrypts 8 blocks at once, so it is slower than `Ttable` on bulk data and much slower on single blocks
+	Bitsliced,
+	/// x86/x86_64 AES-NI
 	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-	{
-		if is_x86_feature_detected!("aes") && is_x86_feature_detected!("sse2") {
-			return unsafe { simd::cipher(state, round_keys) };
+	Simd,
+	/// x86/x86_64 AES-NI, with VAES on 256-bit (AVX2) registers for bulk encryption
+	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+	Vaes256,
+	/// x86/x86_64 AES-NI, with VAES on 512-bit (AVX-512) registers for bulk encryption
+	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+	Vaes512
+}
+
+impl fmt::Display for Backend {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let name = match self {
+			Backend::Sisd => "sisd",
+			Backend::Ttable => "ttable",
+			Backend::Bitsliced => "bitsliced",
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Simd => "simd",
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Vaes256 => "vaes256",
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Vaes512 => "vaes512"
+		};
+		write!(f, "{}", name)
+	}
+}
+
+/// The backend set by `Backend::set_default`, as its index in `Backend::ALL` plus one, or 0 if none is set
+static DEFAULT_BACKEND: AtomicUsize = AtomicUsize::new(0);
+
+impl Backend {
+	/// Every backend built for this architecture, the portable ones first and then the hardware ones from slowest to fastest - Not all of them are necessarily supported by the CPU
+	pub const ALL: &'static [Backend] = &[
+		Backend::Sisd,
+		Backend::Ttable,
+		Backend::Bitsliced,
+		#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+		Backend::Simd,
+		#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+		Backend::Vaes256,
+		#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+		Backend::Vaes512
+	];
+
+	/// The portable backend that `Backend::detect` falls back to when the CPU supports no hardware one - `Sisd`, or `Bitsliced` or `Ttable` if the `constant-time` or `ttable` feature is enabled (`constant-time` taking priority)
+	pub const PORTABLE: Backend = if cfg!(feature = "constant-time") {
+		Backend::Bitsliced
+	} else if cfg!(feature = "ttable") {
+		Backend::Ttable
+	} else {
+		Backend::Sisd
+	};
+
+	/// Returns the fastest hardware backend the CPU supports, or `Backend::PORTABLE` if there is none - The CPU features are only checked the first time this is called
+	pub fn detect() -> Backend {
+		static DETECTED: OnceLock<Backend> = OnceLock::new();
+
+		*DETECTED.get_or_init(|| Backend::ALL.iter().copied().rev().take_while(|b| !b.is_portable()).find(|b| b.is_supported()).unwrap_or(Backend::PORTABLE))
+	}
+
+	/// Returns the backend used by `ExpandedKey::new` and the free functions - The one set by `Backend::set_default`, or `Backend::detect` if none is set
+	pub fn current() -> Backend {
+		match DEFAULT_BACKEND.load(Ordering::Relaxed) {
+			0 => Backend::detect(),
+			n => Backend::ALL[n - 1]
+		}
+	}
+
+	/// Sets the backend used by `ExpandedKey::new` and the free functions for the whole process, for example to use `Backend::Bitsliced` everywhere, or `None` to go back to `Backend::detect` - Keys that were already expanded keep their backend
+	///
+	/// Returns `AesError::UnsupportedBackend` if the CPU does not support `backend`, leaving the current backend unchanged
+	pub fn set_default(backend: Option<Backend>) -> Result<(), AesError> {
+		let n = match backend {
+			None => 0,
+			Some(backend) if !backend.is_supported() => return Err(AesError::UnsupportedBackend(backend)),
+			Some(backend) => Backend::ALL.iter().position(|&b| b == backend).unwrap() + 1
+		};
+		DEFAULT_BACKEND.store(n, Ordering::Relaxed);
+
+		Ok(())
+	}
+
+	/// Returns the backends the CPU supports, in the order of `Backend::ALL`
+	pub fn supported() -> Vec<Backend> {
+		Backend::ALL.iter().copied().filter(|b| b.is_supported()).collect()
+	}
+
+	/// Returns whether the CPU supports this backend
+	pub fn is_supported(self) -> bool {
+		match self {
+			Backend::Sisd | Backend::Ttable | Backend::Bitsliced => true,
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Simd => is_x86_feature_detected!("aes") && is_x86_feature_detected!("sse2"),
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Vaes256 => Backend::Simd.is_supported() && is_x86_feature_detected!("vaes") && is_x86_feature_detected!("avx2"),
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Vaes512 => Backend::Simd.is_supported() && is_x86_feature_detected!("vaes") && is_x86_feature_detected!("avx512f")
+		}
+	}
+
+	/// Returns whether this is one of the portable backends, which every CPU supports
+	fn is_portable(self) -> bool {
+		matches!(self, Backend::Sisd | Backend::Ttable | Backend::Bitsliced)
+	}
+
+	/// Expands a 128, 192 or 256-bit key in FIPS-197 byte order into 11, 13 or 15 128-bit round keys respectively
+	/// # Panics
+	/// This function panics if `key` is not 16, 24 or 32 bytes long
+	fn key_expansion(self, key: &[u8]) -> RoundKeys {
+		let word = |i: usize| u128::from_be_bytes(key[i..(i + 16)].try_into().unwrap());
+		let half = |i: usize| u64::from_be_bytes(key[i..(i + 8)].try_into().unwrap());
+
+		match self {
+			Backend::Sisd => match key.len() {
+				16 => RoundKeys::from_array(sisd::key_expansion(word(0))),
+				24 => RoundKeys::from_array(sisd::key_expansion_192([half(0), half(8), half(16)])),
+				32 => RoundKeys::from_array(sisd::key_expansion_256([word(0), word(16)])),
+				len => panic!("Invalid AES key length: {} bytes", len)
+			},
+			Backend::Ttable => match key.len() {
+				16 => RoundKeys::from_array(ttable::key_expansion(word(0))),
+				24 => RoundKeys::from_array(ttable::key_expansion_192([half(0), half(8), half(16)])),
+				32 => RoundKeys::from_array(ttable::key_expansion_256([word(0), word(16)])),
+				len => panic!("Invalid AES key length: {} bytes", len)
+			},
+			Backend::Bitsliced => match key.len() {
+				16 => RoundKeys::from_array(bitsliced::key_expansion(word(0))),
+				24 => RoundKeys::from_array(bitsliced::key_expansion_192([half(0), half(8), half(16)])),
+				32 => RoundKeys::from_array(bitsliced::key_expansion_256([word(0), word(16)])),
+				len => panic!("Invalid AES key length: {} bytes", len)
+			},
+			// `ExpandedKey` only holds a Simd variant if the CPU supports it
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Simd | Backend::Vaes256 | Backend::Vaes512 => unsafe {
+				match key.len() {
+					16 => RoundKeys::from_array(simd::key_expansion(word(0))),
+					24 => RoundKeys::from_array(simd::key_expansion_192([half(0), half(8), half(16)])),
+					32 => RoundKeys::from_array(simd::key_expansion_256([word(0), word(16)])),
+					len => panic!("Invalid AES key length: {} bytes", len)
+				}
+			}
 		}
 	}
 
-	sisd::cipher(state, round_keys)
-}
\ No newline at end of file
+	/// Performs the cipher on a 128-bit state with 11, 13 or 15 128-bit round keys (AES-128, AES-192 or AES-256 respectively)
+	/// # Panics
+	/// This function panics if `round_keys` length is not equal to 11, 13 or 15
+	fn cipher(self, state: u128, round_keys: &[u128]) -> u128 {
+		assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+		match self {
+			Backend::Sisd => sisd::cipher(state, round_keys),
+			Backend::Ttable => ttable::cipher(state, round_keys),
+			Backend::Bitsliced => bitsliced::cipher(state, round_keys),
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Simd | Backend::Vaes256 | Backend::Vaes512 => unsafe { simd::cipher(state, round_keys) }
+		}
+	}
+
+	/// Performs the cipher on each 128-bit state in `states` in-place with 11, 13 or 15 128-bit round keys - The VAES backends encrypt several blocks per instruction, and AES-NI uses a pipelined kernel that encrypts several blocks at once
+	/// # Panics
+	/// This function panics if `round_keys` length is not equal to 11, 13 or 15
+	fn cipher_blocks(self, states: &mut [u128], round_keys: &[u128]) {
+		assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+		match self {
+			Backend::Sisd => sisd::cipher_blocks(states, round_keys),
+			Backend::Ttable => ttable::cipher_blocks(states, round_keys),
+			Backend::Bitsliced => bitsliced::cipher_blocks(states, round_keys),
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Simd => unsafe { simd::cipher_blocks(states, round_keys) },
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Vaes256 => unsafe { vaes::cipher_blocks_256(states, round_keys) },
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Vaes512 => unsafe { vaes::cipher_blocks_512(states, round_keys) }
+		}
+	}
+
+	/// Transforms the round keys produced by `Backend::key_expansion` into the decryption round keys used by `Backend::inv_cipher`
+	/// # Panics
+	/// This function panics if `round_keys` length is not equal to 11, 13 or 15
+	fn inv_key_expansion(self, round_keys: &[u128]) -> RoundKeys {
+		match self {
+			Backend::Sisd => RoundKeys::from_vec(sisd::inv_key_expansion(round_keys)),
+			Backend::Ttable => RoundKeys::from_vec(ttable::inv_key_expansion(round_keys)),
+			Backend::Bitsliced => RoundKeys::from_vec(bitsliced::inv_key_expansion(round_keys)),
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Simd | Backend::Vaes256 | Backend::Vaes512 => RoundKeys::from_vec(unsafe { simd::inv_key_expansion(round_keys) })
+		}
+	}
+
+	/// Performs the inverse cipher on a 128-bit state with 11, 13 or 15 128-bit decryption round keys produced by `Backend::inv_key_expansion`
+	/// # Panics
+	/// This function panics if `dec_round_keys` length is not equal to 11, 13 or 15
+	fn inv_cipher(self, state: u128, dec_round_keys: &[u128]) -> u128 {
+		match self {
+			Backend::Sisd => sisd::inv_cipher(state, dec_round_keys),
+			Backend::Ttable => ttable::inv_cipher(state, dec_round_keys),
+			Backend::Bitsliced => bitsliced::inv_cipher(state, dec_round_keys),
+			#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+			Backend::Simd | Backend::Vaes256 | Backend::Vaes512 => unsafe { simd::inv_cipher(state, dec_round_keys) }
+		}
+	}
+}
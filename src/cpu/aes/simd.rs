@@ -1,7 +1,9 @@
 #[cfg(target_arch = "x86")]
-use core::arch::x86::{__m128i, _mm_aeskeygenassist_si128, _mm_aesenc_si128, _mm_aesenclast_si128, _mm_shuffle_epi32, _mm_slli_si128, _mm_xor_si128};
+use core::arch::x86::{__m128i, _mm_aesdec_si128, _mm_aesdeclast_si128, _mm_aesimc_si128, _mm_aeskeygenassist_si128, _mm_aesenc_si128, _mm_aesenclast_si128, _mm_castpd_si128, _mm_castsi128_pd, _mm_shuffle_epi32, _mm_shuffle_pd, _mm_slli_si128, _mm_xor_si128};
 #[cfg(target_arch = "x86_64")]
-use core::arch::x86_64::{__m128i, _mm_aeskeygenassist_si128, _mm_aesenc_si128, _mm_aesenclast_si128, _mm_shuffle_epi32, _mm_slli_si128, _mm_xor_si128};
+use core::arch::x86_64::{__m128i, _mm_aesdec_si128, _mm_aesdeclast_si128, _mm_aesimc_si128, _mm_aeskeygenassist_si128, _mm_aesenc_si128, _mm_aesenclast_si128, _mm_castpd_si128, _mm_castsi128_pd, _mm_shuffle_epi32, _mm_shuffle_pd, _mm_slli_si128, _mm_xor_si128};
+
+use super::zeroize::zeroize;
 
 /// Round constants; Not right padded
 const RCON: [i32; 10] = [
@@ -38,6 +40,7 @@ macro_rules! key_expand_i {
 	};
 }
 
+/// Expands one 128-bit key into 11 128-bit round keys (AES-128)
 #[target_feature(enable = "aes")]
 pub unsafe fn key_expansion(key: u128) -> [u128; 11] {
 	let rk0 = key;
@@ -71,17 +74,222 @@ unsafe fn key_expansion_assist(mut xmm1: __m128i, mut xmm2: __m128i) -> __m128i
 	xmm1
 }
 
+/// Expands one 192-bit key, given as 3 64-bit words with the most significant first, into 13 128-bit round keys (AES-192)
+///
+/// Each call to `_mm_aeskeygenassist_si128` produces 6 new 32-bit words of key schedule, which don't line up with the 4-word round keys, so `_mm_shuffle_pd` is used to stitch the round keys together from the halves
+#[target_feature(enable = "aes")]
+pub unsafe fn key_expansion_192(key: [u64; 3]) -> [u128; 13] {
+	let mut xmm1 = to_sse_128((((key[0] as u128) << 64) | (key[1] as u128)).to_be());
+	let mut xmm3 = to_sse_128(((key[2] as u128) << 64).to_be()); // Only the lower 64 bits (in SSE order) are meaningful
+
+	let mut rks = [to_sse_128(0); 13];
+	rks[0] = xmm1;
+	rks[1] = xmm3;
+
+	key_expansion_192_assist(&mut xmm1, _mm_aeskeygenassist_si128::<0x01>(xmm3), &mut xmm3);
+	rks[1] = shuffle_pd::<0>(rks[1], xmm1);
+	rks[2] = shuffle_pd::<1>(xmm1, xmm3);
+	key_expansion_192_assist(&mut xmm1, _mm_aeskeygenassist_si128::<0x02>(xmm3), &mut xmm3);
+	rks[3] = xmm1;
+	rks[4] = xmm3;
+	key_expansion_192_assist(&mut xmm1, _mm_aeskeygenassist_si128::<0x04>(xmm3), &mut xmm3);
+	rks[4] = shuffle_pd::<0>(rks[4], xmm1);
+	rks[5] = shuffle_pd::<1>(xmm1, xmm3);
+	key_expansion_192_assist(&mut xmm1, _mm_aeskeygenassist_si128::<0x08>(xmm3), &mut xmm3);
+	rks[6] = xmm1;
+	rks[7] = xmm3;
+	key_expansion_192_assist(&mut xmm1, _mm_aeskeygenassist_si128::<0x10>(xmm3), &mut xmm3);
+	rks[7] = shuffle_pd::<0>(rks[7], xmm1);
+	rks[8] = shuffle_pd::<1>(xmm1, xmm3);
+	key_expansion_192_assist(&mut xmm1, _mm_aeskeygenassist_si128::<0x20>(xmm3), &mut xmm3);
+	rks[9] = xmm1;
+	rks[10] = xmm3;
+	key_expansion_192_assist(&mut xmm1, _mm_aeskeygenassist_si128::<0x40>(xmm3), &mut xmm3);
+	rks[10] = shuffle_pd::<0>(rks[10], xmm1);
+	rks[11] = shuffle_pd::<1>(xmm1, xmm3);
+	key_expansion_192_assist(&mut xmm1, _mm_aeskeygenassist_si128::<0x80>(xmm3), &mut xmm3);
+	rks[12] = xmm1;
+
+	let round_keys = rks.map(|rk| from_sse_128(rk).to_be());
+	zeroize(&mut rks);
+
+	round_keys
+}
+
+/// Processes the output of `_mm_aeskeygenassist_si128` (`xmm2`) to produce the next 6 words of an AES-192 key schedule in `xmm1` (4 words) and the lower half of `xmm3` (2 words)
+#[target_feature(enable = "sse2")]
+unsafe fn key_expansion_192_assist(xmm1: &mut __m128i, mut xmm2: __m128i, xmm3: &mut __m128i) {
+	let mut xmm4: __m128i;
+
+	xmm2 = _mm_shuffle_epi32::<0x55>(xmm2);
+	xmm4 = _mm_slli_si128::<4>(*xmm1);
+	*xmm1 = _mm_xor_si128(*xmm1, xmm4);
+	xmm4 = _mm_slli_si128::<4>(xmm4);
+	*xmm1 = _mm_xor_si128(*xmm1, xmm4);
+	xmm4 = _mm_slli_si128::<4>(xmm4);
+	*xmm1 = _mm_xor_si128(*xmm1, xmm4);
+	*xmm1 = _mm_xor_si128(*xmm1, xmm2);
+	xmm2 = _mm_shuffle_epi32::<0xff>(*xmm1);
+	xmm4 = _mm_slli_si128::<4>(*xmm3);
+	*xmm3 = _mm_xor_si128(*xmm3, xmm4);
+	*xmm3 = _mm_xor_si128(*xmm3, xmm2);
+}
+
+/// `_mm_shuffle_pd` on integer vectors - Takes 64-bit lane `IMM8 & 1` of `a` as the lower lane and 64-bit lane `(IMM8 >> 1) & 1` of `b` as the upper lane
+#[target_feature(enable = "sse2")]
+unsafe fn shuffle_pd<const IMM8: i32>(a: __m128i, b: __m128i) -> __m128i {
+	_mm_castpd_si128(_mm_shuffle_pd::<IMM8>(_mm_castsi128_pd(a), _mm_castsi128_pd(b)))
+}
+
+/// Expands one 256-bit key, given as 2 128-bit words with the most significant first, into 15 128-bit round keys (AES-256)
+#[target_feature(enable = "aes")]
+pub unsafe fn key_expansion_256(key: [u128; 2]) -> [u128; 15] {
+	let mut xmm1 = to_sse_128(key[0].to_be());
+	let mut xmm3 = to_sse_128(key[1].to_be());
+
+	let mut rks = [to_sse_128(0); 15];
+	rks[0] = xmm1;
+	rks[1] = xmm3;
+
+	xmm1 = key_expansion_assist(xmm1, _mm_aeskeygenassist_si128::<0x01>(xmm3));
+	xmm3 = key_expansion_256_assist(xmm3, xmm1);
+	rks[2] = xmm1;
+	rks[3] = xmm3;
+	xmm1 = key_expansion_assist(xmm1, _mm_aeskeygenassist_si128::<0x02>(xmm3));
+	xmm3 = key_expansion_256_assist(xmm3, xmm1);
+	rks[4] = xmm1;
+	rks[5] = xmm3;
+	xmm1 = key_expansion_assist(xmm1, _mm_aeskeygenassist_si128::<0x04>(xmm3));
+	xmm3 = key_expansion_256_assist(xmm3, xmm1);
+	rks[6] = xmm1;
+	rks[7] = xmm3;
+	xmm1 = key_expansion_assist(xmm1, _mm_aeskeygenassist_si128::<0x08>(xmm3));
+	xmm3 = key_expansion_256_assist(xmm3, xmm1);
+	rks[8] = xmm1;
+	rks[9] = xmm3;
+	xmm1 = key_expansion_assist(xmm1, _mm_aeskeygenassist_si128::<0x10>(xmm3));
+	xmm3 = key_expansion_256_assist(xmm3, xmm1);
+	rks[10] = xmm1;
+	rks[11] = xmm3;
+	xmm1 = key_expansion_assist(xmm1, _mm_aeskeygenassist_si128::<0x20>(xmm3));
+	xmm3 = key_expansion_256_assist(xmm3, xmm1);
+	rks[12] = xmm1;
+	rks[13] = xmm3;
+	xmm1 = key_expansion_assist(xmm1, _mm_aeskeygenassist_si128::<0x40>(xmm3));
+	rks[14] = xmm1;
+
+	let round_keys = rks.map(|rk| from_sse_128(rk).to_be());
+	zeroize(&mut rks);
+
+	round_keys
+}
+
+/// Produces the odd round keys of an AES-256 key schedule from the previous odd round key (`xmm3`) and the even round key just produced (`xmm1`) - These use SubWord without RotWord or a round constant
+#[target_feature(enable = "aes")]
+unsafe fn key_expansion_256_assist(mut xmm3: __m128i, xmm1: __m128i) -> __m128i {
+	let mut xmm4: __m128i;
+
+	let xmm2 = _mm_shuffle_epi32::<0xaa>(_mm_aeskeygenassist_si128::<0x00>(xmm1));
+	xmm4 = _mm_slli_si128::<4>(xmm3);
+	xmm3 = _mm_xor_si128(xmm3, xmm4);
+	xmm4 = _mm_slli_si128::<4>(xmm3);
+	xmm3 = _mm_xor_si128(xmm3, xmm4);
+	xmm4 = _mm_slli_si128::<4>(xmm3);
+	xmm3 = _mm_xor_si128(xmm3, xmm4);
+	_mm_xor_si128(xmm3, xmm2)
+}
+
+/// Performs the cipher on a 128-bit state with 11, 13 or 15 128-bit round keys (AES-128, AES-192 or AES-256 respectively)
 #[target_feature(enable = "aes")]
 pub unsafe fn cipher(state: u128, round_keys: &[u128]) -> u128 {
-	assert_eq!(round_keys.len(), 11);
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
 
 	let mut state = to_sse_128(state.to_be());
 
 	state = _mm_xor_si128(state, to_sse_128(round_keys[0].to_be()));
-	for i in 1..10 {
-		state = _mm_aesenc_si128(state, to_sse_128(round_keys[i].to_be()));
+	for rk in &round_keys[1..nr] {
+		state = _mm_aesenc_si128(state, to_sse_128(rk.to_be()));
 	}
-	state = _mm_aesenclast_si128(state, to_sse_128(round_keys[10].to_be()));
+	state = _mm_aesenclast_si128(state, to_sse_128(round_keys[nr].to_be()));
 
 	from_sse_128(state).to_be()
-}
\ No newline at end of file
+}
+
+/// The number of blocks `cipher_blocks` encrypts at once
+pub const PIPELINE_WIDTH: usize = 8;
+
+/// Performs the cipher on each 128-bit state in `states` with 11, 13 or 15 128-bit round keys, `PIPELINE_WIDTH` states at a time
+///
+/// AESENC has a latency of several cycles but can be issued every cycle, so interleaving the rounds of independent blocks keeps the AES unit busy instead of waiting on each round of a single block. Any states left over are encrypted one at a time
+#[target_feature(enable = "aes")]
+pub unsafe fn cipher_blocks(states: &mut [u128], round_keys: &[u128]) {
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
+
+	// Convert the round keys once rather than once per block
+	let mut rks = [to_sse_128(0); 15];
+	for (rk, round_key) in rks.iter_mut().zip(round_keys) {
+		*rk = to_sse_128(round_key.to_be());
+	}
+
+	let mut chunks = states.chunks_exact_mut(PIPELINE_WIDTH);
+
+	for chunk in &mut chunks {
+		let mut blocks = [to_sse_128(0); PIPELINE_WIDTH];
+		for i in 0..PIPELINE_WIDTH {
+			blocks[i] = _mm_xor_si128(to_sse_128(chunk[i].to_be()), rks[0]);
+		}
+		for rk in &rks[1..nr] {
+			for block in blocks.iter_mut() {
+				*block = _mm_aesenc_si128(*block, *rk);
+			}
+		}
+		for i in 0..PIPELINE_WIDTH {
+			chunk[i] = from_sse_128(_mm_aesenclast_si128(blocks[i], rks[nr])).to_be();
+		}
+	}
+
+	for state in chunks.into_remainder() {
+		*state = cipher(*state, round_keys);
+	}
+
+	zeroize(&mut rks);
+}
+
+/// Transforms the round keys produced by `key_expansion` into the decryption round keys used by `inv_cipher` (the key schedule of the FIPS-197 equivalent inverse cipher) - The round keys are reversed, and `_mm_aesimc_si128` (InvMixColumns) is applied to all but the first and last
+#[target_feature(enable = "aes")]
+pub unsafe fn inv_key_expansion(round_keys: &[u128]) -> Vec<u128> {
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
+
+	let mut dec_rks = Vec::with_capacity(round_keys.len());
+	dec_rks.push(round_keys[nr]);
+	for rk in round_keys[1..nr].iter().rev() {
+		dec_rks.push(from_sse_128(_mm_aesimc_si128(to_sse_128(rk.to_be()))).to_be());
+	}
+	dec_rks.push(round_keys[0]);
+
+	dec_rks
+}
+
+/// Performs the inverse cipher on a 128-bit state with 11, 13 or 15 128-bit decryption round keys produced by `inv_key_expansion`
+#[target_feature(enable = "aes")]
+pub unsafe fn inv_cipher(state: u128, dec_round_keys: &[u128]) -> u128 {
+	assert!(matches!(dec_round_keys.len(), 11 | 13 | 15));
+
+	let nr = dec_round_keys.len() - 1;
+
+	let mut state = to_sse_128(state.to_be());
+
+	state = _mm_xor_si128(state, to_sse_128(dec_round_keys[0].to_be()));
+	for rk in &dec_round_keys[1..nr] {
+		state = _mm_aesdec_si128(state, to_sse_128(rk.to_be()));
+	}
+	state = _mm_aesdeclast_si128(state, to_sse_128(dec_round_keys[nr].to_be()));
+
+	from_sse_128(state).to_be()
+}
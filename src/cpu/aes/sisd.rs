@@ -1,13 +1,18 @@
+// The `gf` macro expands to code that trips this lint
+#![allow(clippy::manual_strip)]
+
 use std::ptr;
 
 use gf256::gf::gf;
 
+use super::zeroize::zeroize;
+
 #[gf(polynomial=0x11b, generator=0x3, barret)]
 /// u8 in GF(2^8)
 type gf256_aes;
 
 /// The Rijndael substitution box
-const S_BOX: [u8; 256] = [
+pub(super) const S_BOX: [u8; 256] = [
 	0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
 	0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
 	0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
@@ -26,33 +31,95 @@ const S_BOX: [u8; 256] = [
 	0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
 ];
 
+/// The inverse of the Rijndael substitution box
+pub(super) const INV_S_BOX: [u8; 256] = [
+	0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+	0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+	0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+	0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+	0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+	0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+	0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+	0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+	0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+	0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+	0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+	0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+	0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+	0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+	0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+	0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
 /// Round constants; Right padded with 3 bytes of 0x00
 const RCON: [u32; 10] = [
 	0x01000000, 0x02000000, 0x04000000, 0x08000000, 0x10000000, 0x20000000, 0x40000000, 0x80000000,
 	0x1b000000, 0x36000000,
 ];
 
+/// Expands one 128-bit key into 11 128-bit round keys (AES-128)
 pub fn key_expansion(key: u128) -> [u128; 11] {
-	let mut rks: [u128; 11] = [0; 11];
+	expand_words(words_128(key), sub_word)
+}
 
-	rks[0] = key;
+/// Expands one 192-bit key, given as 3 64-bit words with the most significant first, into 13 128-bit round keys (AES-192)
+pub fn key_expansion_192(key: [u64; 3]) -> [u128; 13] {
+	expand_words(words_192(key), sub_word)
+}
 
-	for i in 1..rks.len() {
-		let rcon = RCON[i - 1];
+/// Expands one 256-bit key, given as 2 128-bit words with the most significant first, into 15 128-bit round keys (AES-256)
+pub fn key_expansion_256(key: [u128; 2]) -> [u128; 15] {
+	expand_words(words_256(key), sub_word)
+}
 
-		let k0 = (rks[i - 1] >> 96) as u32;
-		let k1 = (rks[i - 1] >> 64) as u32;
-		let k2 = (rks[i - 1] >> 32) as u32;
-		let k3 = rks[i - 1] as u32;
+/// Splits a 128-bit key into 32-bit words, most significant first
+pub(super) fn words_128(key: u128) -> [u32; 4] {
+	[(key >> 96) as u32, (key >> 64) as u32, (key >> 32) as u32, key as u32]
+}
 
-		let w0 = k0 ^ sub_word(k3.rotate_left(8)) ^ rcon;
-		let w1 = k1 ^ w0;
-		let w2 = k2 ^ w1;
-		let w3 = k3 ^ w2;
+/// Splits a 192-bit key, given as 3 64-bit words with the most significant first, into 32-bit words, most significant first
+pub(super) fn words_192(key: [u64; 3]) -> [u32; 6] {
+	[
+		(key[0] >> 32) as u32, key[0] as u32,
+		(key[1] >> 32) as u32, key[1] as u32,
+		(key[2] >> 32) as u32, key[2] as u32
+	]
+}
+
+/// Splits a 256-bit key, given as 2 128-bit words with the most significant first, into 32-bit words, most significant first
+pub(super) fn words_256(key: [u128; 2]) -> [u32; 8] {
+	[
+		(key[0] >> 96) as u32, (key[0] >> 64) as u32, (key[0] >> 32) as u32, key[0] as u32,
+		(key[1] >> 96) as u32, (key[1] >> 64) as u32, (key[1] >> 32) as u32, key[1] as u32
+	]
+}
+
+/// The FIPS-197 KeyExpansion routine, generic over the number of 32-bit words in the key (`NK`) and the number of round keys (`NR1`, which is the number of rounds + 1)
+///
+/// `sub_word` applies the S-box to each byte of a word, so that other software backends can share the key schedule with their own S-box
+pub(super) fn expand_words<const NK: usize, const NR1: usize>(key: [u32; NK], sub_word: fn(u32) -> u32) -> [u128; NR1] {
+	// The key schedule as 32-bit words, 4 words per round key
+	let mut w = [0u32; 60];
+	w[..NK].copy_from_slice(&key);
+
+	for i in NK..(NR1 * 4) {
+		let mut temp = w[i - 1];
+		if i % NK == 0 {
+			temp = sub_word(temp.rotate_left(8)) ^ RCON[i / NK - 1];
+		} else if NK > 6 && i % NK == 4 {
+			temp = sub_word(temp);
+		}
+		w[i] = w[i - NK] ^ temp;
+	}
 
-		rks[i] = ((w0 as u128) << 96) | ((w1 as u128) << 64) | ((w2 as u128) << 32) | (w3 as u128)
+	let mut rks = [0u128; NR1];
+	for (i, rk) in rks.iter_mut().enumerate() {
+		*rk = ((w[i * 4] as u128) << 96) | ((w[i * 4 + 1] as u128) << 64) | ((w[i * 4 + 2] as u128) << 32) | (w[i * 4 + 3] as u128);
 	}
 
+	// The key schedule is key material, so it is wiped rather than left on the stack
+	zeroize(&mut w);
+
 	rks
 }
 
@@ -65,26 +132,36 @@ fn sub_word(w: u32) -> u32 {
 	((b3 as u32) << 24) | ((b2 as u32) << 16) | ((b1 as u32) << 8) | (b0 as u32)
 }
 
+/// Performs the cipher on a 128-bit state with 11, 13 or 15 128-bit round keys (AES-128, AES-192 or AES-256 respectively)
 pub fn cipher(mut state: u128, round_keys: &[u128]) -> u128 {
-	assert_eq!(round_keys.len(), 11);
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
 
 	// MSB -> LSB
 	// b0 -> b15
 
 	state ^= round_keys[0];
-	for i in 1..10 {
+	for rk in &round_keys[1..nr] {
 		state = sub_bytes(state);
 		state = shift_rows(state);
 		state = mix_columns(state);
-		state ^= round_keys[i];
+		state ^= rk;
 	}
 	state = sub_bytes(state);
 	state = shift_rows(state);
-	state ^= round_keys[10];
+	state ^= round_keys[nr];
 
 	state
 }
 
+/// Performs the cipher on each 128-bit state in `states` in-place, one at a time
+pub fn cipher_blocks(states: &mut [u128], round_keys: &[u128]) {
+	for state in states {
+		*state = cipher(*state, round_keys);
+	}
+}
+
 fn sub_bytes(state: u128) -> u128 {
 	// TODO: This is probably horrifically badly performing I should just use bitmasks or something. Decide on how to handle this
 	u128::from_be_bytes(
@@ -146,7 +223,7 @@ fn mix_columns(state: u128) -> u128 {
 	for i in 0..4 {
 		// i is the column index
 
-		let a0 = gf256_aes(state[(i * 4)]);
+		let a0 = gf256_aes(state[i * 4]);
 		let a1 = gf256_aes(state[(i * 4) + 1]);
 		let a2 = gf256_aes(state[(i * 4) + 2]);
 		let a3 = gf256_aes(state[(i * 4) + 3]);
@@ -156,7 +233,89 @@ fn mix_columns(state: u128) -> u128 {
 		let a2_res = a0                + a1                + gf256_aes(2) * a2 + gf256_aes(3) * a3;
 		let a3_res = gf256_aes(3) * a0 + a1                + a2                + gf256_aes(2) * a3;
 
-		state[(i * 4)] = a0_res.get();
+		state[i * 4] = a0_res.get();
+		state[(i * 4) + 1] = a1_res.get();
+		state[(i * 4) + 2] = a2_res.get();
+		state[(i * 4) + 3] = a3_res.get();
+	}
+
+	u128::from_be_bytes(state)
+}
+
+/// Transforms the round keys produced by `key_expansion` into the decryption round keys used by `inv_cipher` (the key schedule of the FIPS-197 equivalent inverse cipher) - The round keys are reversed, and InvMixColumns is applied to all but the first and last
+pub fn inv_key_expansion(round_keys: &[u128]) -> Vec<u128> {
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
+
+	let mut dec_rks = Vec::with_capacity(round_keys.len());
+	dec_rks.push(round_keys[nr]);
+	dec_rks.extend(round_keys[1..nr].iter().rev().map(|rk| inv_mix_columns(*rk)));
+	dec_rks.push(round_keys[0]);
+
+	dec_rks
+}
+
+/// Performs the inverse cipher on a 128-bit state with 11, 13 or 15 128-bit decryption round keys produced by `inv_key_expansion`
+///
+/// This is the FIPS-197 equivalent inverse cipher, which has the same sequence of steps as the cipher with each replaced by its inverse
+pub fn inv_cipher(mut state: u128, dec_round_keys: &[u128]) -> u128 {
+	assert!(matches!(dec_round_keys.len(), 11 | 13 | 15));
+
+	let nr = dec_round_keys.len() - 1;
+
+	state ^= dec_round_keys[0];
+	for rk in &dec_round_keys[1..nr] {
+		state = inv_sub_bytes(state);
+		state = inv_shift_rows(state);
+		state = inv_mix_columns(state);
+		state ^= rk;
+	}
+	state = inv_sub_bytes(state);
+	state = inv_shift_rows(state);
+	state ^= dec_round_keys[nr];
+
+	state
+}
+
+fn inv_sub_bytes(state: u128) -> u128 {
+	u128::from_be_bytes(state.to_be_bytes().map(|b| INV_S_BOX[b as usize]))
+}
+
+fn inv_shift_rows(state: u128) -> u128 {
+	// State is a column-major 2D array of bytes, so byte (row r, column c) is at index r + 4c
+	let state = state.to_be_bytes();
+	let mut res = [0u8; 16];
+
+	for c in 0..4 {
+		for r in 0..4 {
+			// Row r is rotated right by r places
+			res[r + 4 * ((c + r) % 4)] = state[r + 4 * c];
+		}
+	}
+
+	u128::from_be_bytes(res)
+}
+
+fn inv_mix_columns(state: u128) -> u128 {
+	// Remember state is a column-major 2D array of bytes
+
+	let mut state = state.to_be_bytes();
+
+	for i in 0..4 {
+		// i is the column index
+
+		let a0 = gf256_aes(state[i * 4]);
+		let a1 = gf256_aes(state[(i * 4) + 1]);
+		let a2 = gf256_aes(state[(i * 4) + 2]);
+		let a3 = gf256_aes(state[(i * 4) + 3]);
+
+		let a0_res = gf256_aes(0x0e) * a0 + gf256_aes(0x0b) * a1 + gf256_aes(0x0d) * a2 + gf256_aes(0x09) * a3;
+		let a1_res = gf256_aes(0x09) * a0 + gf256_aes(0x0e) * a1 + gf256_aes(0x0b) * a2 + gf256_aes(0x0d) * a3;
+		let a2_res = gf256_aes(0x0d) * a0 + gf256_aes(0x09) * a1 + gf256_aes(0x0e) * a2 + gf256_aes(0x0b) * a3;
+		let a3_res = gf256_aes(0x0b) * a0 + gf256_aes(0x0d) * a1 + gf256_aes(0x09) * a2 + gf256_aes(0x0e) * a3;
+
+		state[i * 4] = a0_res.get();
 		state[(i * 4) + 1] = a1_res.get();
 		state[(i * 4) + 2] = a2_res.get();
 		state[(i * 4) + 3] = a3_res.get();
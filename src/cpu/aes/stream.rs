@@ -0,0 +1,362 @@
+//! This module implements `std::io` adapters that encrypt or decrypt with AES/CTR on the fly, so that for example `io::copy` from a file into an `AesCtrWriter` encrypts the file
+//!
+//! `AesCtrWriter::with_iv_prefix` writes the IV before the ciphertext, and `AesCtrReader::with_iv_prefix` reads it back, so only the key needs to be known to decrypt. The IV is written in the byte order of the `AesCtr`
+//!
+//! Both adapters can encrypt large pieces of data in parallel on a `ThreadPool` - The writer buffers data until it has enough to be worth splitting between threads, and the reader reads ahead into a buffer of the same kind, so that small reads (such as the 8 KiB ones `io::copy` makes) are still decrypted in parallel
+
+use std::{io::{self, Read, Write}, mem};
+
+use super::{check_key_len, AesCtr, AesError, ByteOrder, CounterLayout};
+use crate::cpu::scoped_thread_pool::ThreadPool;
+
+#[cfg(test)]
+const KEY: [u8; 16] = 0x2b7e151628aed2a6abf7158809cf4f3cu128.to_be_bytes();
+#[cfg(test)]
+const IV: u128 = 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff;
+
+#[cfg(test)]
+#[test]
+fn test_writer_reader() {
+	let plaintext: Vec<u8> = (0..100_000).map(|i| (i * 31) as u8).collect();
+
+	let mut expected = plaintext.clone();
+	super::ExpandedKey::new(&KEY, ByteOrder::Standard).encrypt_decrypt(&mut expected, Some(IV)).unwrap();
+
+	let mut pool = ThreadPool::with_num_workers(4);
+
+	for par in [false, true] {
+		// io::copy writes in small pieces, so this checks that the writer continues the keystream across writes
+		let mut writer = AesCtrWriter::with_iv_prefix(Vec::new(), AesCtr::new(&KEY, IV, CounterLayout::FULL, ByteOrder::Standard));
+		if par {
+			writer = writer.with_thread_pool(&mut pool, 64 * 1024);
+		}
+		io::copy(&mut &plaintext[..], &mut writer).unwrap();
+		let encrypted = writer.into_inner().unwrap();
+
+		assert_eq!(&encrypted[..16], &IV.to_be_bytes(), "[ERROR]: The IV prefix was not written");
+		assert_eq!(&encrypted[16..], &expected[..], "[ERROR]: Encrypting with the writer differs from encrypting the whole message");
+
+		let mut reader = AesCtrReader::with_iv_prefix(&encrypted[..], &KEY, CounterLayout::FULL, ByteOrder::Standard).unwrap();
+		assert_eq!(reader.ctr().iv(), IV);
+
+		let mut decrypted = Vec::new();
+		if par {
+			let mut reader = reader.with_thread_pool(&mut pool, 64 * 1024);
+
+			// A small read fills the whole buffer from the inner reader, so that it is decrypted in parallel
+			let mut buf = [0u8; 100];
+			let n = reader.read(&mut buf).unwrap();
+			decrypted.extend_from_slice(&buf[..n]);
+			assert_eq!(reader.get_ref().len(), encrypted.len() - 16 - 64 * 1024, "[ERROR]: The reader did not read ahead into its buffer");
+
+			io::copy(&mut reader, &mut decrypted).unwrap();
+		} else {
+			reader.read_to_end(&mut decrypted).unwrap();
+		}
+
+		assert_eq!(decrypted, plaintext, "[ERROR]: Decrypting with the reader does not give back the plaintext");
+	}
+}
+
+#[cfg(test)]
+#[test]
+fn test_reader_counter_end() {
+	// Room for one block before the 32-bit counter wraps
+	let iv = (IV & !0xffffffff) | 0xffffffff;
+	let ctr = || AesCtr::new(&KEY, iv, CounterLayout::NONCE_96_COUNTER_32, ByteOrder::Standard);
+
+	let mut encrypted = [0x5au8; 16];
+	ctr().apply_keystream(&mut encrypted).unwrap();
+
+	// A message that ends exactly at the end of the keystream reads to the end
+	let mut decrypted = Vec::new();
+	AesCtrReader::new(&encrypted[..], ctr()).read_to_end(&mut decrypted).unwrap();
+	assert_eq!(decrypted, [0x5au8; 16]);
+
+	// A longer message is only read as far as the keystream goes, so the rest is left in the inner reader
+	let long = [0u8; 20];
+	let mut reader = AesCtrReader::new(&long[..], ctr());
+	let mut buf = [0u8; 64];
+	assert_eq!(reader.read(&mut buf).unwrap(), 16);
+	assert_eq!(reader.get_ref().len(), 4);
+	assert_eq!(reader.read(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+#[cfg(test)]
+#[test]
+fn test_writer_drop() {
+	let mut encrypted = Vec::new();
+
+	{
+		let mut writer = AesCtrWriter::new(&mut encrypted, AesCtr::new(&KEY, IV, CounterLayout::FULL, ByteOrder::Legacy));
+		writer.write_all(b"hello, ").unwrap();
+		writer.write_all(b"world").unwrap();
+	}
+
+	let mut expected = b"hello, world".to_vec();
+	super::aes_encrypt_decrypt(&mut expected, &KEY, Some(IV));
+
+	assert_eq!(encrypted, expected, "[ERROR]: Buffered data was not written when the writer was dropped");
+}
+
+/// Pieces of data at least this long are encrypted in parallel if a `ThreadPool` is provided, and the smallest buffer size used with one - Smaller pieces are quicker to encrypt on one thread than to split up
+const PAR_MIN_LEN: usize = 64 * 1024;
+
+/// Converts an IV into bytes in byte order `order`, as the IV prefix
+fn iv_to_bytes(iv: u128, order: ByteOrder) -> [u8; 16] {
+	match order {
+		ByteOrder::Legacy => iv.to_le_bytes(),
+		ByteOrder::Standard => iv.to_be_bytes()
+	}
+}
+
+/// Converts an IV prefix in byte order `order` into an IV
+fn iv_from_bytes(bytes: [u8; 16], order: ByteOrder) -> u128 {
+	match order {
+		ByteOrder::Legacy => u128::from_le_bytes(bytes),
+		ByteOrder::Standard => u128::from_be_bytes(bytes)
+	}
+}
+
+/// A `Write` adapter that encrypts everything written to it with an `AesCtr` before writing it to the inner writer
+///
+/// Like `BufWriter`, encrypted data is kept in a buffer until the next write or flush, so that a write is never half-encrypted if the inner writer fails. Buffered data is written when the writer is dropped, but any error is ignored, so call `flush` or `into_inner` to handle errors
+pub struct AesCtrWriter<'p, W: Write> {
+	inner: Option<W>,
+	ctr: AesCtr,
+	/// The first `encrypted` bytes are ciphertext waiting to be written to `inner`, and the rest is plaintext waiting to be encrypted
+	buf: Vec<u8>,
+	encrypted: usize,
+	/// Plaintext is buffered until there is at least this much, then encrypted
+	buffer_size: usize,
+	pool: Option<&'p mut ThreadPool>
+}
+
+impl<'p, W: Write> AesCtrWriter<'p, W> {
+	/// Creates a writer that encrypts with `ctr` and writes to `inner`
+	pub fn new(inner: W, ctr: AesCtr) -> Self {
+		AesCtrWriter { inner: Some(inner), ctr, buf: Vec::new(), encrypted: 0, buffer_size: 0, pool: None }
+	}
+
+	/// Creates a writer that writes the IV of `ctr` to `inner`, then encrypts with `ctr` and writes to `inner` - This is read back by `AesCtrReader::with_iv_prefix`
+	///
+	/// Use `AesCtr::with_random_iv` to encrypt with a new random IV
+	pub fn with_iv_prefix(inner: W, ctr: AesCtr) -> Self {
+		let mut writer = Self::new(inner, ctr);
+		writer.buf.extend_from_slice(&iv_to_bytes(writer.ctr.iv(), writer.ctr.key().order()));
+		writer.encrypted = writer.buf.len();
+		writer
+	}
+
+	/// Encrypts in parallel using the threads of `pool` - Written data is buffered until there is at least `buffer_size` bytes, which are then encrypted together, so `buffer_size` should be large enough to be worth splitting between threads - Sizes below 64 KiB are raised to 64 KiB
+	pub fn with_thread_pool(mut self, pool: &'p mut ThreadPool, buffer_size: usize) -> Self {
+		self.pool = Some(pool);
+		self.buffer_size = buffer_size.max(PAR_MIN_LEN);
+		self
+	}
+
+	/// Returns a reference to the inner writer
+	pub fn get_ref(&self) -> &W {
+		self.inner.as_ref().unwrap()
+	}
+
+	/// Returns a mutable reference to the inner writer - Writing to it directly will corrupt the output
+	pub fn get_mut(&mut self) -> &mut W {
+		self.inner.as_mut().unwrap()
+	}
+
+	/// Returns the `AesCtr`, whose position is the number of bytes encrypted so far - Data still buffered as plaintext is not counted
+	pub fn ctr(&self) -> &AesCtr {
+		&self.ctr
+	}
+
+	/// Encrypts and writes all buffered data, then returns the inner writer
+	pub fn into_inner(mut self) -> io::Result<W> {
+		self.flush_buf()?;
+		Ok(self.inner.take().unwrap())
+	}
+
+	/// Encrypts the buffered plaintext, leaving the whole buffer as ciphertext
+	fn encrypt_buf(&mut self) -> io::Result<()> {
+		let data = &mut self.buf[self.encrypted..];
+		let res = match self.pool.as_deref_mut() {
+			Some(pool) => self.ctr.apply_keystream_par(data, pool),
+			None => self.ctr.apply_keystream(data)
+		};
+		res.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+		self.encrypted = self.buf.len();
+
+		Ok(())
+	}
+
+	/// Writes the buffered ciphertext to the inner writer, keeping whatever could not be written if there is an error
+	fn write_encrypted(&mut self) -> io::Result<()> {
+		let inner = self.inner.as_mut().unwrap();
+
+		let mut written = 0;
+		let res = loop {
+			if written == self.encrypted {
+				break Ok(());
+			}
+			match inner.write(&self.buf[written..self.encrypted]) {
+				Ok(0) => break Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write the buffered data")),
+				Ok(n) => written += n,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+				Err(e) => break Err(e)
+			}
+		};
+
+		self.buf.drain(..written);
+		self.encrypted -= written;
+
+		res
+	}
+
+	/// Encrypts and writes all buffered data
+	fn flush_buf(&mut self) -> io::Result<()> {
+		self.encrypt_buf()?;
+		self.write_encrypted()
+	}
+}
+
+impl<W: Write> Write for AesCtrWriter<'_, W> {
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		// Ciphertext left over from an earlier write is written first, as nothing of `data` has been taken yet if it fails
+		self.write_encrypted()?;
+
+		self.buf.extend_from_slice(data);
+
+		if self.buf.len() - self.encrypted >= self.buffer_size {
+			if let Err(e) = self.encrypt_buf() {
+				self.buf.truncate(self.buf.len() - data.len());
+				return Err(e);
+			}
+		}
+
+		Ok(data.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.flush_buf()?;
+		self.get_mut().flush()
+	}
+}
+
+impl<W: Write> Drop for AesCtrWriter<'_, W> {
+	fn drop(&mut self) {
+		if self.inner.is_some() {
+			let _ = self.flush_buf();
+		}
+	}
+}
+
+/// A `Read` adapter that decrypts everything read from the inner reader with an `AesCtr`
+///
+/// Without a `ThreadPool`, each read is passed straight to the inner reader. With one, like `BufReader`, reads smaller than the buffer size fill a buffer from the inner reader instead, which is decrypted in parallel and then handed out
+pub struct AesCtrReader<'p, R: Read> {
+	inner: R,
+	ctr: AesCtr,
+	/// Decrypted data read ahead from `inner`, of which the first `read` bytes have been handed out
+	buf: Vec<u8>,
+	read: usize,
+	/// The number of bytes to read ahead into `buf`
+	buffer_size: usize,
+	pool: Option<&'p mut ThreadPool>
+}
+
+impl<'p, R: Read> AesCtrReader<'p, R> {
+	/// Creates a reader that reads from `inner` and decrypts with `ctr`
+	pub fn new(inner: R, ctr: AesCtr) -> Self {
+		AesCtrReader { inner, ctr, buf: Vec::new(), read: 0, buffer_size: 0, pool: None }
+	}
+
+	/// Reads the IV written by `AesCtrWriter::with_iv_prefix` from `inner`, then creates a reader that reads from `inner` and decrypts with slice `key` (`key` having gone through necessary key derivation and being exactly 128, 192 or 256-bit) and that IV, with the counter laid out according to `layout`, and `key`, data and the IV taken in byte order `order`
+	///
+	/// Returns an error of kind `InvalidInput` if `key` is not 128, 192 or 256-bit (16, 24 or 32 bytes), before anything is read
+	pub fn with_iv_prefix(mut inner: R, key: &[u8], layout: CounterLayout, order: ByteOrder) -> io::Result<Self> {
+		check_key_len(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+		let mut iv = [0u8; 16];
+		inner.read_exact(&mut iv)?;
+
+		Ok(Self::new(inner, AesCtr::new(key, iv_from_bytes(iv, order), layout, order)))
+	}
+
+	/// Decrypts in parallel using the threads of `pool` - Reads smaller than `buffer_size` read `buffer_size` bytes ahead from the inner reader, which are then decrypted together, so `buffer_size` should be large enough to be worth splitting between threads - Sizes below 64 KiB are raised to 64 KiB
+	pub fn with_thread_pool(mut self, pool: &'p mut ThreadPool, buffer_size: usize) -> Self {
+		self.pool = Some(pool);
+		self.buffer_size = buffer_size.max(PAR_MIN_LEN);
+		self
+	}
+
+	/// Returns a reference to the inner reader
+	pub fn get_ref(&self) -> &R {
+		&self.inner
+	}
+
+	/// Returns a mutable reference to the inner reader - Reading from it directly will corrupt the output
+	pub fn get_mut(&mut self) -> &mut R {
+		&mut self.inner
+	}
+
+	/// Returns the `AesCtr`, whose position is the number of bytes decrypted so far - Data read ahead into the buffer but not yet read is counted
+	pub fn ctr(&self) -> &AesCtr {
+		&self.ctr
+	}
+
+	/// Returns the inner reader - Data read ahead into the buffer but not yet read is lost
+	pub fn into_inner(self) -> R {
+		self.inner
+	}
+
+	/// Reads the next part of the message from `inner` into `buf` and decrypts it, in parallel if it is long enough and there is a `ThreadPool`
+	fn read_decrypt(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let len = buf.len().min(self.ctr.remaining().try_into().unwrap_or(usize::MAX));
+
+		if len == 0 && !buf.is_empty() {
+			return match self.inner.read(&mut [0u8; 1])? {
+				0 => Ok(0),
+				_ => Err(io::Error::new(io::ErrorKind::InvalidData, AesError::CounterOverflow))
+			};
+		}
+
+		let buf = &mut buf[..len];
+		let n = self.inner.read(buf)?;
+
+		let res = match self.pool.as_deref_mut() {
+			Some(pool) if n >= PAR_MIN_LEN => self.ctr.apply_keystream_par(&mut buf[..n], pool),
+			_ => self.ctr.apply_keystream(&mut buf[..n])
+		};
+		res.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+		Ok(n)
+	}
+}
+
+impl<R: Read> Read for AesCtrReader<'_, R> {
+	/// Reads and decrypts the next part of the message - Returns an error of kind `InvalidData` if the message continues past the end of the keystream
+	///
+	/// Only as much is read from `inner` as the keystream can decrypt, so no data that was read is lost to the error. Only at the very end of the keystream is one byte read to tell whether the message goes on
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if self.read == self.buf.len() {
+			// Reads at least as large as the buffer gain nothing from going through it
+			if buf.len() >= self.buffer_size {
+				return self.read_decrypt(buf);
+			}
+
+			let mut ahead = mem::take(&mut self.buf);
+			ahead.resize(self.buffer_size, 0);
+			let res = self.read_decrypt(&mut ahead);
+			ahead.truncate(*res.as_ref().unwrap_or(&0));
+			self.buf = ahead;
+			self.read = 0;
+			res?;
+		}
+
+		let n = buf.len().min(self.buf.len() - self.read);
+		buf[..n].copy_from_slice(&self.buf[self.read..(self.read + n)]);
+		self.read += n;
+
+		Ok(n)
+	}
+}
@@ -0,0 +1,104 @@
+//! This module implements portable AES with 32-bit T-tables, which combine SubBytes, ShiftRows and MixColumns into 16 table lookups and XORs per round. It is `Backend::Ttable`, which is used instead of `sisd` when the `ttable` feature is enabled and AES-NI is not available
+//!
+//! The state is handled as 4 32-bit columns, with row 0 in the most significant byte. Like `sisd`, the tables are indexed by secret data, so this is not constant-time
+
+use super::sisd::{self, INV_S_BOX, S_BOX};
+
+pub use sisd::{inv_key_expansion, key_expansion, key_expansion_192, key_expansion_256};
+
+/// The encryption tables - `TE[0][x]` is the MixColumns column of S-box output `S[x]` in row 0, i.e. (2S[x], S[x], S[x], 3S[x]), and `TE[n]` is `TE[0]` rotated right by `n` bytes for row `n`
+const TE: [[u32; 256]; 4] = tables(&S_BOX, [2, 1, 1, 3]);
+/// The decryption tables - `TD[0][x]` is the InvMixColumns column of inverse S-box output `S'[x]` in row 0, i.e. (14S'[x], 9S'[x], 13S'[x], 11S'[x]), and `TD[n]` is `TD[0]` rotated right by `n` bytes for row `n`
+const TD: [[u32; 256]; 4] = tables(&INV_S_BOX, [14, 9, 13, 11]);
+
+/// Multiplies `a` and `b` in GF(2^8) with the AES polynomial
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+	let mut res = 0;
+	while b != 0 {
+		if b & 1 != 0 {
+			res ^= a;
+		}
+		a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+		b >>= 1;
+	}
+	res
+}
+
+/// Builds the 4 T-tables for `s_box`, with `coeffs` being the first column of the (Inv)MixColumns matrix
+const fn tables(s_box: &[u8; 256], coeffs: [u8; 4]) -> [[u32; 256]; 4] {
+	let mut t = [[0u32; 256]; 4];
+	let mut x = 0;
+	while x < 256 {
+		let s = s_box[x];
+		let col = u32::from_be_bytes([gf_mul(s, coeffs[0]), gf_mul(s, coeffs[1]), gf_mul(s, coeffs[2]), gf_mul(s, coeffs[3])]);
+		t[0][x] = col;
+		t[1][x] = col.rotate_right(8);
+		t[2][x] = col.rotate_right(16);
+		t[3][x] = col.rotate_right(24);
+		x += 1;
+	}
+	t
+}
+
+/// Splits a 128-bit state into its 4 columns
+fn columns(state: u128) -> [u32; 4] {
+	[(state >> 96) as u32, (state >> 64) as u32, (state >> 32) as u32, state as u32]
+}
+
+fn from_columns(s: [u32; 4]) -> u128 {
+	((s[0] as u128) << 96) | ((s[1] as u128) << 64) | ((s[2] as u128) << 32) | (s[3] as u128)
+}
+
+/// Byte `n` of a column, counting from row 0 (the most significant byte), as a table index
+fn byte(w: u32, n: usize) -> usize {
+	((w >> (24 - 8 * n)) & 0xff) as usize
+}
+
+/// Performs the cipher on a 128-bit state with 11, 13 or 15 128-bit round keys (AES-128, AES-192 or AES-256 respectively)
+pub fn cipher(state: u128, round_keys: &[u128]) -> u128 {
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
+
+	let mut s = columns(state ^ round_keys[0]);
+	for rk in &round_keys[1..nr] {
+		let rk = columns(*rk);
+		// ShiftRows moves row r of column (c + r) into column c
+		s = std::array::from_fn(|c| {
+			TE[0][byte(s[c], 0)] ^ TE[1][byte(s[(c + 1) % 4], 1)] ^ TE[2][byte(s[(c + 2) % 4], 2)] ^ TE[3][byte(s[(c + 3) % 4], 3)] ^ rk[c]
+		});
+	}
+	// The last round has no MixColumns
+	s = std::array::from_fn(|c| u32::from_be_bytes([0, 1, 2, 3].map(|r| S_BOX[byte(s[(c + r) % 4], r)])));
+
+	from_columns(s) ^ round_keys[nr]
+}
+
+/// Performs the cipher on each 128-bit state in `states` in-place, one at a time
+pub fn cipher_blocks(states: &mut [u128], round_keys: &[u128]) {
+	for state in states {
+		*state = cipher(*state, round_keys);
+	}
+}
+
+/// Performs the inverse cipher on a 128-bit state with 11, 13 or 15 128-bit decryption round keys produced by `inv_key_expansion`
+///
+/// This is the FIPS-197 equivalent inverse cipher, which has the same sequence of steps as the cipher with each replaced by its inverse
+pub fn inv_cipher(state: u128, dec_round_keys: &[u128]) -> u128 {
+	assert!(matches!(dec_round_keys.len(), 11 | 13 | 15));
+
+	let nr = dec_round_keys.len() - 1;
+
+	let mut s = columns(state ^ dec_round_keys[0]);
+	for rk in &dec_round_keys[1..nr] {
+		let rk = columns(*rk);
+		// InvShiftRows moves row r of column (c - r) into column c
+		s = std::array::from_fn(|c| {
+			TD[0][byte(s[c], 0)] ^ TD[1][byte(s[(c + 3) % 4], 1)] ^ TD[2][byte(s[(c + 2) % 4], 2)] ^ TD[3][byte(s[(c + 1) % 4], 3)] ^ rk[c]
+		});
+	}
+	// The last round has no InvMixColumns
+	s = std::array::from_fn(|c| u32::from_be_bytes([0, 1, 2, 3].map(|r| INV_S_BOX[byte(s[(c + 4 - r) % 4], r)])));
+
+	from_columns(s) ^ dec_round_keys[nr]
+}
@@ -0,0 +1,110 @@
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__m128i, __m256i, __m512i, _mm256_aesenc_epi128, _mm256_aesenclast_epi128, _mm256_broadcastsi128_si256, _mm256_loadu_si256, _mm256_storeu_si256, _mm256_xor_si256, _mm512_aesenc_epi128, _mm512_aesenclast_epi128, _mm512_broadcast_i32x4, _mm512_loadu_si512, _mm512_storeu_si512, _mm512_xor_si512};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__m128i, __m256i, __m512i, _mm256_aesenc_epi128, _mm256_aesenclast_epi128, _mm256_broadcastsi128_si256, _mm256_loadu_si256, _mm256_storeu_si256, _mm256_xor_si256, _mm512_aesenc_epi128, _mm512_aesenclast_epi128, _mm512_broadcast_i32x4, _mm512_loadu_si512, _mm512_storeu_si512, _mm512_xor_si512};
+
+use super::{simd, zeroize::zeroize};
+
+/// The number of 512-bit registers (of 4 blocks each) `cipher_blocks_512` encrypts at once
+const PIPELINE_WIDTH_512: usize = 4;
+/// The number of 256-bit registers (of 2 blocks each) `cipher_blocks_256` encrypts at once
+const PIPELINE_WIDTH_256: usize = 8;
+
+/// Transforms a rust u128 to an SSE __m128i using std::mem::transmute
+unsafe fn to_sse_128(n: u128) -> __m128i {
+	std::mem::transmute(n)
+}
+
+/// Performs the cipher on each 128-bit state in `states` with 11, 13 or 15 128-bit round keys using 512-bit VAES instructions, which encrypt 4 blocks each
+///
+/// `PIPELINE_WIDTH_512` registers are interleaved to hide the instruction latency, as in `simd::cipher_blocks`. Any states left over are passed on to `simd::cipher_blocks`
+#[target_feature(enable = "vaes,avx512f,aes")]
+pub unsafe fn cipher_blocks_512(states: &mut [u128], round_keys: &[u128]) {
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
+
+	// Each round key broadcast into all 4 lanes
+	let mut rks = [_mm512_broadcast_i32x4(to_sse_128(0)); 15];
+	for (rk, round_key) in rks.iter_mut().zip(round_keys) {
+		*rk = _mm512_broadcast_i32x4(to_sse_128(round_key.to_be()));
+	}
+
+	let mut chunks = states.chunks_exact_mut(PIPELINE_WIDTH_512 * 4);
+
+	for chunk in &mut chunks {
+		// Byte-reverse each block into the order the AES instructions expect, as `simd` does with to_be
+		let mut bytes = [0u128; PIPELINE_WIDTH_512 * 4];
+		for (b, state) in bytes.iter_mut().zip(chunk.iter()) {
+			*b = state.to_be();
+		}
+
+		let mut blocks = [rks[0]; PIPELINE_WIDTH_512];
+		for (i, block) in blocks.iter_mut().enumerate() {
+			*block = _mm512_xor_si512(_mm512_loadu_si512(bytes.as_ptr().add(i * 4) as *const __m512i), rks[0]);
+		}
+		for rk in &rks[1..nr] {
+			for block in blocks.iter_mut() {
+				*block = _mm512_aesenc_epi128(*block, *rk);
+			}
+		}
+		for (i, block) in blocks.iter().enumerate() {
+			_mm512_storeu_si512(bytes.as_mut_ptr().add(i * 4) as *mut __m512i, _mm512_aesenclast_epi128(*block, rks[nr]));
+		}
+
+		for (state, b) in chunk.iter_mut().zip(bytes) {
+			*state = b.to_be();
+		}
+	}
+
+	simd::cipher_blocks(chunks.into_remainder(), round_keys);
+
+	zeroize(&mut rks);
+}
+
+/// Performs the cipher on each 128-bit state in `states` with 11, 13 or 15 128-bit round keys using 256-bit VAES instructions, which encrypt 2 blocks each
+///
+/// `PIPELINE_WIDTH_256` registers are interleaved to hide the instruction latency, as in `simd::cipher_blocks`. Any states left over are passed on to `simd::cipher_blocks`
+#[target_feature(enable = "vaes,avx2,aes")]
+pub unsafe fn cipher_blocks_256(states: &mut [u128], round_keys: &[u128]) {
+	assert!(matches!(round_keys.len(), 11 | 13 | 15));
+
+	let nr = round_keys.len() - 1;
+
+	// Each round key broadcast into both lanes
+	let mut rks = [_mm256_broadcastsi128_si256(to_sse_128(0)); 15];
+	for (rk, round_key) in rks.iter_mut().zip(round_keys) {
+		*rk = _mm256_broadcastsi128_si256(to_sse_128(round_key.to_be()));
+	}
+
+	let mut chunks = states.chunks_exact_mut(PIPELINE_WIDTH_256 * 2);
+
+	for chunk in &mut chunks {
+		// Byte-reverse each block into the order the AES instructions expect, as `simd` does with to_be
+		let mut bytes = [0u128; PIPELINE_WIDTH_256 * 2];
+		for (b, state) in bytes.iter_mut().zip(chunk.iter()) {
+			*b = state.to_be();
+		}
+
+		let mut blocks = [rks[0]; PIPELINE_WIDTH_256];
+		for (i, block) in blocks.iter_mut().enumerate() {
+			*block = _mm256_xor_si256(_mm256_loadu_si256(bytes.as_ptr().add(i * 2) as *const __m256i), rks[0]);
+		}
+		for rk in &rks[1..nr] {
+			for block in blocks.iter_mut() {
+				*block = _mm256_aesenc_epi128(*block, *rk);
+			}
+		}
+		for (i, block) in blocks.iter().enumerate() {
+			_mm256_storeu_si256(bytes.as_mut_ptr().add(i * 2) as *mut __m256i, _mm256_aesenclast_epi128(*block, rks[nr]));
+		}
+
+		for (state, b) in chunk.iter_mut().zip(bytes) {
+			*state = b.to_be();
+		}
+	}
+
+	simd::cipher_blocks(chunks.into_remainder(), round_keys);
+
+	zeroize(&mut rks);
+}
@@ -0,0 +1,86 @@
+//! This module wipes key material from memory once it is no longer needed, so that keys and round keys don't linger in freed heap memory or old stack frames where a memory disclosure bug or a core dump could reveal them
+//!
+//! The writes are volatile, so the compiler can't remove them as dead stores even though the memory is never read again. Copies that the compiler itself makes in registers or spills to the stack can't be reached this way, so this covers every buffer the code creates, but not every temporary
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__m128i, __m256i, __m512i};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__m128i, __m256i, __m512i};
+use std::{mem, ops::{Deref, DerefMut}, ptr, sync::atomic::{compiler_fence, Ordering}};
+
+#[cfg(test)]
+#[test]
+fn test_zeroize() {
+	let mut buf = Zeroizing::from_slice(&[0x0123456789abcdefu128; 15]);
+	zeroize(&mut buf);
+	assert!(buf.iter().all(|x| *x == 0), "[ERROR]: The buffer was not zeroed");
+
+	let mut arr = [[0xffu8; 16]; 4];
+	zeroize(&mut arr);
+	assert_eq!(arr, [[0u8; 16]; 4], "[ERROR]: The array was not zeroed");
+}
+
+/// Types that are made only of integers, so that all zero bytes is a valid value
+/// # Safety
+/// Implementing this for a type for which all zero bytes is not a valid value (such as a reference or a `NonZero` integer) makes `zeroize` undefined behaviour
+pub(super) unsafe trait Zeroable: Copy {}
+
+unsafe impl Zeroable for u8 {}
+unsafe impl Zeroable for u32 {}
+unsafe impl Zeroable for u64 {}
+unsafe impl Zeroable for u128 {}
+unsafe impl<T: Zeroable, const N: usize> Zeroable for [T; N] {}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe impl Zeroable for __m128i {}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe impl Zeroable for __m256i {}
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe impl Zeroable for __m512i {}
+
+/// Overwrites every element of `data` with zeros, with writes the compiler can't optimise away
+pub(super) fn zeroize<T: Zeroable>(data: &mut [T]) {
+	for x in data.iter_mut() {
+		// All zero bytes is a valid T, and x is a valid, aligned pointer
+		unsafe { ptr::write_volatile(x, mem::zeroed()) };
+	}
+
+	// Stop the compiler from moving later memory accesses before the wipe
+	compiler_fence(Ordering::SeqCst);
+}
+
+/// A heap buffer of key material that is zeroed when it is dropped
+pub(super) struct Zeroizing<T: Zeroable>(Box<[T]>);
+
+impl<T: Zeroable> Zeroizing<T> {
+	/// Copies `data` into a new buffer
+	pub(super) fn from_slice(data: &[T]) -> Zeroizing<T> {
+		Zeroizing(data.into())
+	}
+
+	/// Moves the elements of `data` into a new buffer, zeroing `data` before it is freed
+	pub(super) fn from_vec(mut data: Vec<T>) -> Zeroizing<T> {
+		let buf = Zeroizing::from_slice(&data);
+		zeroize(&mut data);
+		buf
+	}
+}
+
+impl<T: Zeroable> Deref for Zeroizing<T> {
+	type Target = [T];
+
+	fn deref(&self) -> &[T] {
+		&self.0
+	}
+}
+
+impl<T: Zeroable> DerefMut for Zeroizing<T> {
+	fn deref_mut(&mut self) -> &mut [T] {
+		&mut self.0
+	}
+}
+
+impl<T: Zeroable> Drop for Zeroizing<T> {
+	fn drop(&mut self) {
+		zeroize(&mut self.0);
+	}
+}
@@ -1,4 +1,4 @@
-//! This module contains submodules to implement AES-128/CTR on the CPU and a thread pool
+//! This module contains submodules to implement AES/CTR on the CPU and a thread pool
 
 pub mod aes;
 pub mod scoped_thread_pool;
\ No newline at end of file
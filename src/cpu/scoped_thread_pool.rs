@@ -1,6 +1,6 @@
 //! This module implements a thread pool that works within a certain scope to allow safe referencing of local variables/non `'static`s
 
-use std::{sync::{mpsc, Arc, Mutex, atomic::{AtomicUsize, Ordering}, Condvar}, thread, marker::PhantomData};
+use std::{any::Any, collections::VecDeque, io, mem, ops::Range, panic::{self, AssertUnwindSafe}, sync::{mpsc, Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}, Condvar}, thread, marker::PhantomData};
 
 #[cfg(test)]
 #[test]
@@ -21,81 +21,437 @@ fn test_thread_pool() {
 	assert_eq!((0..32000).map(|_| 1).collect::<Vec<u8>>(), *arr.lock().unwrap());
 }
 
+#[cfg(test)]
+#[test]
+fn test_spawn() {
+	let mut tp = ThreadPool::with_num_workers(4);
+
+	let data: Vec<u64> = (0..10000).collect();
+
+	let (sums, failed) = tp.scoped(|scope| {
+		// Each task borrows its part of `data` and returns its sum
+		let handles: Vec<TaskHandle<u64>> = data.chunks(1000).map(|chunk| scope.spawn(move || chunk.iter().sum())).collect();
+		let failed = scope.spawn(|| -> u64 { panic!("task failed") });
+
+		// Joining a handle inside the scope waits for that task only
+		let sums: Vec<u64> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+		(sums, failed)
+	});
+
+	assert_eq!(sums.iter().sum::<u64>(), data.iter().sum(), "[ERROR]: The task results do not add up to the sum of the data");
+
+	// The scope has waited for the task, so it can also be joined afterwards
+	assert!(failed.is_finished());
+	let payload = failed.join().unwrap_err();
+	assert_eq!(payload.downcast_ref::<&str>(), Some(&"task failed"), "[ERROR]: The panic payload was not returned by join");
+
+	// The pool still works after a spawned task panicked
+	let n = tp.scoped(|scope| scope.spawn(|| 1 + 1).join().unwrap());
+	assert_eq!(n, 2);
+}
+
+#[cfg(test)]
+#[test]
+fn test_panic() {
+	let mut tp = ThreadPool::with_num_workers(2);
+
+	let completed = AtomicUsize::new(0);
+
+	// More panicking tasks than workers, so the workers must survive them to run the rest
+	let res = panic::catch_unwind(AssertUnwindSafe(|| {
+		tp.scoped(|scope| {
+			for i in 0..100 {
+				let completed = &completed;
+				scope.assign_task(move || {
+					if i % 10 == 0 {
+						panic!("task {} failed", i);
+					}
+					completed.fetch_add(1, Ordering::SeqCst);
+				});
+			}
+		})
+	}));
+
+	let payload = res.expect_err("[ERROR]: The panic of a task was not re-raised by scoped");
+	assert!(payload.downcast_ref::<String>().unwrap().ends_with("failed"), "[ERROR]: The payload of the task's panic was not re-raised");
+	assert_eq!(completed.load(Ordering::SeqCst), 90, "[ERROR]: Not every other task ran");
+
+	// A spawned task's panic is only re-raised if its handle is dropped without being joined
+	let res = panic::catch_unwind(AssertUnwindSafe(|| tp.scoped(|scope| {
+		let _ = scope.spawn(|| panic!("not joined"));
+	})));
+	assert!(res.is_err(), "[ERROR]: The panic of an unjoined task was not re-raised");
+
+	tp.scoped(|scope| assert!(scope.spawn(|| panic!("joined")).join().is_err()));
+
+	// A handle returned from its scope keeps its task's panic, rather than leaving it for a later scope when dropped
+	let handle = tp.scoped(|scope| scope.spawn(|| panic!("outlived its scope")));
+	drop(handle);
+	assert!(tp.shared.panic.lock().unwrap().is_none(), "[ERROR]: The panic of a handle dropped after its scope was reported to the pool");
+
+	// The pool still works, with all its workers
+	let completed = AtomicUsize::new(0);
+	tp.scoped(|scope| {
+		for _ in 0..1000 {
+			scope.assign_task(|| { completed.fetch_add(1, Ordering::SeqCst); });
+		}
+	});
+	assert_eq!(completed.load(Ordering::SeqCst), 1000);
+}
+
+#[cfg(test)]
+#[test]
+fn test_all_workers_used() {
+	use std::{sync::Barrier, time::Duration};
+
+	let mut tp = ThreadPool::with_num_workers(4);
+
+	// Each task waits for all the others, so this only finishes if every task runs at the same time, on its own worker
+	let barrier = Barrier::new(4);
+	tp.scoped(|scope| {
+		for _ in 0..4 {
+			scope.assign_task(|| { barrier.wait(); });
+			thread::sleep(Duration::from_millis(10));
+		}
+	});
+}
+
+#[cfg(test)]
+#[test]
+fn test_data_parallel() {
+	let mut tp = ThreadPool::with_num_workers(4);
+
+	// Not a multiple of the chunk size or the number of workers
+	let mut data: Vec<u64> = vec![0; 1003];
+
+	let (sum, runs) = tp.scoped(|scope| {
+		scope.par_chunks_mut(&mut data, 10, |first_chunk, run| {
+			assert!(run.len() % 10 == 0 || first_chunk * 10 + run.len() == 1003, "[ERROR]: A run was split inside a chunk");
+			for (i, x) in run.iter_mut().enumerate() {
+				*x = (first_chunk * 10 + i) as u64;
+			}
+		});
+
+		let counted: Vec<AtomicUsize> = (0..100).map(|_| AtomicUsize::new(0)).collect();
+		scope.parallel_for(0..100, |i| { counted[i].fetch_add(1, Ordering::SeqCst); });
+		assert!(counted.iter().all(|c| c.load(Ordering::SeqCst) == 1), "[ERROR]: An index was not visited exactly once");
+
+		// The last run ends at the end of the range rather than overflowing past it
+		let top = AtomicUsize::new(0);
+		scope.parallel_for((usize::MAX - 10)..usize::MAX, |_| { top.fetch_add(1, Ordering::SeqCst); });
+		assert_eq!(top.load(Ordering::SeqCst), 10);
+
+		// Concatenation is not commutative, so this checks the results are combined in order
+		let runs = scope.par_map_reduce(&[1, 2, 3, 4, 5, 6, 7], 1, |_, run| run.to_vec(), |mut a, b| { a.extend(b); a });
+		(scope.par_map_reduce(&data, 16, |_, run| run.iter().sum::<u64>(), |a, b| a + b), runs)
+	});
+
+	assert_eq!(data, (0..1003).collect::<Vec<u64>>());
+	assert_eq!(sum, Some((0..1003).sum()));
+	assert_eq!(runs, Some(vec![1, 2, 3, 4, 5, 6, 7]));
+	assert_eq!(tp.scoped(|scope| scope.par_map_reduce(&[] as &[u8], 1, |_, _| 0, |a, b| a + b)), None);
+
+	let res = panic::catch_unwind(AssertUnwindSafe(|| tp.scoped(|scope| scope.parallel_for(0..100, |i| assert!(i != 50, "index failed")))));
+	assert!(res.is_err(), "[ERROR]: The panic of a call of f was not re-raised");
+}
+
+#[cfg(test)]
+#[test]
+fn test_builder() {
+	let started = Arc::new(AtomicUsize::new(0));
+	let exited = Arc::new(AtomicUsize::new(0));
+
+	let (started2, exited2) = (Arc::clone(&started), Arc::clone(&exited));
+	let mut tp = ThreadPoolBuilder::new()
+		.num_workers(3)
+		.thread_name_prefix("test-worker-")
+		.stack_size(256 * 1024)
+		.on_start(move |_| { started2.fetch_add(1, Ordering::SeqCst); })
+		.on_exit(move |_| { exited2.fetch_add(1, Ordering::SeqCst); })
+		.build()
+		.unwrap();
+
+	assert_eq!(tp.num_workers(), 3);
+	assert_eq!(started.load(Ordering::SeqCst), 3, "[ERROR]: build returned before every start hook ran");
+
+	let names = Mutex::new(Vec::new());
+	tp.scoped(|scope| {
+		scope.parallel_for(0..100, |_| names.lock().unwrap().push(thread::current().name().unwrap().to_string()));
+	});
+	assert!(names.into_inner().unwrap().iter().all(|name| ["test-worker-0", "test-worker-1", "test-worker-2"].contains(&name.as_str())), "[ERROR]: A task ran on a thread that was not named with the prefix");
+
+	drop(tp);
+	assert_eq!(exited.load(Ordering::SeqCst), 3, "[ERROR]: Not every exit hook ran");
+
+	// Pin to a CPU this thread is allowed to run on, as a cpuset may exclude any given CPU - No machine has a CPU past the end of the affinity mask
+	#[cfg(target_os = "linux")]
+	{
+		// SAFETY: `cpu_set_t` is a plain bitset, so all zeroes is a valid empty set for `sched_getaffinity` to fill in
+		let allowed = unsafe {
+			let mut set: libc::cpu_set_t = mem::zeroed();
+			assert_eq!(libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set), 0);
+			(0..libc::CPU_SETSIZE as usize).find(|&cpu| libc::CPU_ISSET(cpu, &set)).unwrap()
+		};
+
+		let mut tp = ThreadPoolBuilder::new().num_workers(2).cpu_affinity([allowed]).build().unwrap();
+		assert_eq!(tp.scoped(|scope| scope.spawn(|| 1 + 1).join().unwrap()), 2);
+
+		assert!(ThreadPoolBuilder::new().num_workers(2).cpu_affinity([usize::MAX]).build().is_err());
+	}
+
+	assert!(ThreadPoolBuilder::new().num_workers(2).on_start(|i| assert!(i != 1, "start failed")).build().is_err(), "[ERROR]: A panicking start hook did not make build fail");
+}
+
+#[cfg(test)]
+#[test]
+#[ignore = "benchmark - run with `cargo test --release -- --ignored --nocapture`"]
+fn bench_aes_block_tasks() {
+	use std::time::{Duration, Instant};
+	use super::aes::AesBlock;
+
+	// One task per 16-byte block, so the time is dominated by the scheduler rather than the encryption
+	const LEN: usize = 1024 * 1024;
+	const RUNS: usize = 5;
+
+	/// Times the scheduler `ThreadPool` used before work stealing on `blocks` - Every worker takes boxed tasks from one channel behind a mutex, and notifies the scope thread after each task, as that `ThreadPool` did. The workers are started before the timer
+	fn time_channel(num_workers: usize, blocks: Vec<AesBlock>) -> Duration {
+		let (sender, receiver) = mpsc::channel::<Task>();
+		let receiver = Mutex::new(receiver);
+		let num_tasks = AtomicUsize::new(0);
+		let await_condvar = (Condvar::new(), Mutex::new(()));
+
+		thread::scope(|s| {
+			for _ in 0..num_workers {
+				s.spawn(|| while let Ok(task) = { let receiver = receiver.lock().unwrap(); receiver.recv() } {
+					task.call_once_box();
+					let _guard = await_condvar.1.lock().unwrap();
+					num_tasks.fetch_sub(1, Ordering::SeqCst);
+					await_condvar.0.notify_one();
+				});
+			}
+
+			let start = Instant::now();
+			for mut block in blocks {
+				num_tasks.fetch_add(1, Ordering::SeqCst);
+				sender.send(Box::new(move || block.encrypt())).unwrap();
+			}
+			drop(await_condvar.0.wait_while(await_condvar.1.lock().unwrap(), |_| num_tasks.load(Ordering::SeqCst) > 0).unwrap());
+			let elapsed = start.elapsed();
+
+			drop(sender);
+			elapsed
+		})
+	}
+
+	/// Times `ThreadPool` on `blocks` - The pool is created before the timer
+	fn time_stealing(num_workers: usize, blocks: Vec<AesBlock>) -> Duration {
+		let mut pool = ThreadPool::with_num_workers(num_workers);
+
+		let start = Instant::now();
+		pool.scoped(|scope| {
+			for mut block in blocks {
+				scope.assign_task(move || block.encrypt());
+			}
+		});
+		start.elapsed()
+	}
+
+	let mut data = vec![0u8; LEN];
+	let num_blocks = LEN / 16;
+
+	for num_workers in [1, 4, 16] {
+		// The best of several runs, to leave out runs disturbed by other processes
+		let mut best = [Duration::MAX; 2];
+		for _ in 0..RUNS {
+			best[0] = best[0].min(time_channel(num_workers, AesBlock::decompose(&mut data, &[0x2b; 16], Some(0))));
+			best[1] = best[1].min(time_stealing(num_workers, AesBlock::decompose(&mut data, &[0x2b; 16], Some(0))));
+		}
+
+		for (name, elapsed) in [("channel", best[0]), ("work stealing", best[1])] {
+			println!("{:>2} workers, {:>13}: {:.0} ns per task, {:.0} MB/s", num_workers, name, elapsed.as_nanos() as f64 / num_blocks as f64, LEN as f64 / elapsed.as_secs_f64() / 1e6);
+		}
+
+		assert!(best[1] < best[0], "[ERROR]: Work stealing was slower than the channel with {} workers", num_workers);
+	}
+}
+
+/// The value a panicking task panicked with, as returned by `std::panic::catch_unwind`
+pub type PanicPayload = Box<dyn Any + Send + 'static>;
+
 /// A trait type of a Box (unique pointer) around a function that needs to be called only once (using non-callable trait object workaround) and that is safe to copy/pass between threads and that lives as long as the entire program
 type Task<'a> = Box<dyn FnOnceBox + Send + 'a>;
 
-/// Message enum for passing to worker threads. 2 variants: NewTask and Terminate
-#[allow(dead_code)]
-enum Message {
-	NewTask(Task<'static>),
-	Terminate
-}
+/// The number of times an idle worker yields to other threads before it goes to sleep
+const SPIN_YIELDS: usize = 32;
 
+/// A pool of worker threads that tasks can be assigned to. Tasks are assigned inside `ThreadPool::scoped`, which allows them to borrow local variables
+///
+/// Each worker has its own queue of tasks, and tasks are dealt out to the queues in turn. A worker takes tasks from the front of its own queue, and when that is empty it steals from the back of the others, so workers rarely contend for the same lock
 #[allow(dead_code)]
 pub struct ThreadPool {
 	workers: Vec<Worker>,
-	sender: mpsc::Sender<Message>,
-	num_tasks: Arc<AtomicUsize>,
-	await_condvar: Arc<(Condvar, Mutex<()>)>
+	shared: Arc<Shared>,
+	/// The queue the next task is pushed to
+	next_queue: AtomicUsize
 }
 
-#[allow(dead_code)]
-impl ThreadPool {
-	/// Construct a ThreadPool with a number of worker threads equal to the return value of `std::thread::available_parallelism` if `Some`, or if `None`, then 4
-	pub fn new() -> Self {
-		let num_workers = match thread::available_parallelism() {
-			Ok(n) => n.into(),
-			Err(_) => 4 // Arbitrarily picked
+/// The state shared between the pool and its workers
+struct Shared {
+	/// One queue of tasks per worker
+	queues: Vec<Mutex<VecDeque<Task<'static>>>>,
+	/// The number of tasks in the queues - Only changed while holding the lock of the queue the task is pushed to or taken from, so it is only above 0 while there is a task to find
+	queued: AtomicUsize,
+	/// The number of workers waiting on `sleep_condvar`, so that pushing a task only takes the lock to wake one up if there is one asleep
+	sleeping: AtomicUsize,
+	/// The number of idle workers yielding in `wait_for_task` before they sleep - Such a worker is sure to take a task, unlike a busy one, so pushing a task doesn't need to wake a sleeper for it
+	searching: AtomicUsize,
+	sleep_condvar: (Condvar, Mutex<()>),
+	shutdown: AtomicBool,
+	/// The number of tasks assigned and not yet complete
+	num_tasks: AtomicUsize,
+	/// Notified when `num_tasks` reaches 0, for the `await_all` function
+	await_condvar: (Condvar, Mutex<()>),
+	/// The first panic of a task in the current scope, re-raised when `ThreadPool::scoped` returns
+	panic: Mutex<Option<PanicPayload>>,
+	/// The number of scopes that have ended, so that a `TaskHandle` can tell whether the scope of its task is still running
+	generation: AtomicUsize
+}
+
+impl Shared {
+	/// Pushes `task` to the back of queue `index`, and wakes a worker if any are asleep and there are more queued tasks than searching workers to take them
+	fn push(&self, index: usize, task: Task<'static>) {
+		let queued = {
+			let mut queue = self.queues[index].lock().unwrap();
+			queue.push_back(task);
+			self.queued.fetch_add(1, Ordering::SeqCst) + 1
 		};
 
-		// Create a multiple producer single consumer channel
-		let (sender, reciever) = mpsc::channel();
+		// A worker increments `sleeping` before checking `queued`, and this checks `sleeping` after incrementing `queued`, so either this sees the worker or the worker sees the task
+		// Busy workers may be running long tasks, so only searching workers are relied on to take the task - A searching worker checks `queued` after it stops being counted, so it can't go to sleep without taking a task it was counted for
+		if self.sleeping.load(Ordering::SeqCst) > 0 && queued > self.searching.load(Ordering::SeqCst) {
+			let _guard = self.sleep_condvar.1.lock().unwrap();
+			self.sleep_condvar.0.notify_one();
+		}
+	}
 
-		// Protect the reciever for using across threads
-		let reciever = Arc::new(Mutex::new(reciever));
+	/// Takes a task from the front of queue `index`, or if that is empty, steals one from the back of another queue
+	fn pop(&self, index: usize) -> Option<Task<'static>> {
+		let num_queues = self.queues.len();
 
-		// Preallocate
-		let mut workers = Vec::with_capacity(num_workers);
+		// Only one queue is locked at a time, so two workers stealing from each other can't deadlock
+		let take = |index: usize, front: bool| {
+			let mut queue = self.queues[index].lock().unwrap();
+			let task = if front { queue.pop_front() } else { queue.pop_back() };
+			if task.is_some() {
+				self.queued.fetch_sub(1, Ordering::SeqCst);
+			}
+			task
+		};
 
-		// Use an atomic usize for keeping track of number of tasks
-		let num_tasks = Arc::new(AtomicUsize::new(0));
-		// Create a Condvar and an associated Mutex that will be used for the `await_all` function
-		let await_condvar = Arc::new((Condvar::new(), Mutex::new(())));
+		take(index, true).or_else(|| (1..num_queues).find_map(|offset| take((index + offset) % num_queues, false)))
+	}
 
-		// Create the workers
-		for _ in 0..num_workers {
-			workers.push(Worker::new(Arc::clone(&reciever), Arc::clone(&await_condvar), Arc::clone(&num_tasks)));
+	/// Blocks until there may be a task in the queues - Returns false if the pool is shutting down instead
+	fn wait_for_task(&self) -> bool {
+		// Tasks tend to be assigned in bursts, so yield for a while before going to sleep, to save being woken up again
+		self.searching.fetch_add(1, Ordering::SeqCst);
+		for _ in 0..SPIN_YIELDS {
+			if self.queued.load(Ordering::SeqCst) > 0 {
+				self.searching.fetch_sub(1, Ordering::SeqCst);
+				return true;
+			}
+			thread::yield_now();
 		}
+		self.searching.fetch_sub(1, Ordering::SeqCst);
+
+		let guard = self.sleep_condvar.1.lock().unwrap();
+		self.sleeping.fetch_add(1, Ordering::SeqCst);
+		let _guard = self.sleep_condvar.0.wait_while(guard, |_| {
+			self.queued.load(Ordering::SeqCst) == 0 && !self.shutdown.load(Ordering::SeqCst)
+		}).unwrap();
+		self.sleeping.fetch_sub(1, Ordering::SeqCst);
+
+		!self.shutdown.load(Ordering::SeqCst)
+	}
 
-		ThreadPool {
-			workers,
-			sender,
-			num_tasks,
-			await_condvar
+	/// Decrements `num_tasks`, notifying `await_condvar` if it reaches 0
+	fn task_done(&self) {
+		if self.num_tasks.fetch_sub(1, Ordering::SeqCst) == 1 {
+			// The lock makes sure `await_all` is either before its check of `num_tasks` or already waiting, so the notification can't be missed
+			let _guard = self.await_condvar.1.lock().unwrap();
+			self.await_condvar.0.notify_all();
 		}
 	}
+}
+
+#[allow(dead_code)]
+impl ThreadPool {
+	/// Construct a ThreadPool with a number of worker threads equal to the return value of `std::thread::available_parallelism` if `Some`, or if `None`, then 4
+	/// # Panics
+	/// This function will panic if a worker thread can't be spawned - Use `ThreadPoolBuilder::build` to handle the error
+	pub fn new() -> Self {
+		ThreadPoolBuilder::new().build().expect("failed to spawn worker thread")
+	}
+
+	/// Construct a ThreadPool with `num_workers` worker threads
+	/// # Panics
+	/// This function will panic if `num_workers` is 0, or if a worker thread can't be spawned
+	pub fn with_num_workers(num_workers: usize) -> Self {
+		ThreadPoolBuilder::new().num_workers(num_workers).build().expect("failed to spawn worker thread")
+	}
+
+	/// Returns the number of worker threads in the pool
+	pub fn num_workers(&self) -> usize {
+		self.workers.len()
+	}
 
 	/// This function executes the closure passed in. You can use the argument to create tasks - The closures for those tasks can capture references to variables outside the closure passed in to this function as if they were 'static
 	///
 	/// This function will block until all tasks have finished executing
+	/// # Panics
+	/// If a task panics, the other tasks still run, and once they have all finished this function panics with the payload of the first task that panicked, as `std::thread::scope` does - The pool can still be used afterwards. Panics of tasks created with `ThreadPoolScope::spawn` are only re-raised if their `TaskHandle` was not joined
 	pub fn scoped<'p, 's, F, R>(&'p mut self, scope_fn: F) -> R where F: FnOnce(&ThreadPoolScope<'p, 's>) -> R {
-		let scope = ThreadPoolScope { pool: self, scope: PhantomData };
-		scope_fn(&scope)
+		// A panic left over from a scope that was unwound by its own closure panicking is not this scope's
+		*self.shared.panic.lock().unwrap() = None;
+
+		let shared = Arc::clone(&self.shared);
+
+		let res = {
+			let scope = ThreadPoolScope { pool: self, scope: PhantomData };
+			scope_fn(&scope)
+			// All tasks are complete once the scope is dropped
+		};
+
+		let payload = shared.panic.lock().unwrap().take();
+		if let Some(payload) = payload {
+			panic::resume_unwind(payload);
+		}
+
+		res
+	}
+}
+
+impl Default for ThreadPool {
+	fn default() -> Self {
+		Self::new()
 	}
 }
 
 impl Drop for ThreadPool {
 	/// Define behaviour for when ThreadPool goes out of scope/is dropped - We want to shut down all threads gracefully
 	fn drop(&mut self) {
-		// Instruct all workers to finish
-		for _ in 0..self.workers.len() {
-			self.sender.send(Message::Terminate).unwrap();
+		// Instruct all workers to finish - All tasks are complete, as they can only be assigned inside `scoped`
+		self.shared.shutdown.store(true, Ordering::SeqCst);
+		{
+			let _guard = self.shared.sleep_condvar.1.lock().unwrap();
+			self.shared.sleep_condvar.0.notify_all();
 		}
 
 		// Wait for all workers to finish
 		for worker in &mut self.workers {
 			if let Some(thread) = worker.thread.take() {
-				thread.join().unwrap();
+				// Workers catch the panics of tasks, so there is nothing to report here
+				let _ = thread.join();
 			}
 		}
 	}
@@ -107,31 +463,195 @@ struct Worker {
 }
 
 impl Worker {
-	/// Creates a thread that waits for messages and acts appropriately upon reception of them. The thread also decrements `num_tasks` when a task is completed and notifies `await_condvar`'s Condvar
+	/// Creates a thread that runs tasks from queue `index`, or stolen from the other queues, sleeping while there are none. The thread also decrements `num_tasks` when a task is completed
+	///
+	/// The thread is named, sized and pinned according to `builder`, and calls its start hook, then sends whether it was pinned to `started` - It calls the exit hook once the pool shuts down
+	///
+	/// A task that panics is caught and its panic reported to `shared.panic`, so the thread keeps running and `num_tasks` is still decremented
 	#[allow(dead_code)]
-	fn new(reciever: Arc<Mutex<mpsc::Receiver<Message>>>, await_condvar: Arc<(Condvar, Mutex<()>)>, num_tasks: Arc<AtomicUsize>) -> Self {
-		Worker {
-			thread: Some(thread::spawn(move || {
-				loop {
-					// Recieve a message from the channel (blocking when there are none available)
-					let message = reciever.lock().unwrap().recv().unwrap();
-
-					match message {
-						Message::NewTask(taskptr) => {
-							taskptr.call_once_box();
-						},
-						Message::Terminate => break
-					}
+	fn new(index: usize, shared: Arc<Shared>, builder: &ThreadPoolBuilder, started: mpsc::Sender<io::Result<()>>) -> io::Result<Self> {
+		let mut thread_builder = thread::Builder::new();
+		if let Some(prefix) = &builder.thread_name_prefix {
+			thread_builder = thread_builder.name(format!("{}{}", prefix, index));
+		}
+		if let Some(stack_size) = builder.stack_size {
+			thread_builder = thread_builder.stack_size(stack_size);
+		}
 
-					// Decrement `num_tasks` and notify the condition variable
-					num_tasks.fetch_sub(1, Ordering::SeqCst);
-					await_condvar.0.notify_one();
+		let cpu = builder.cpus.as_ref().map(|cpus| cpus[index % cpus.len()]);
+		let (on_start, on_exit) = (builder.on_start.clone(), builder.on_exit.clone());
+
+		let thread = thread_builder.spawn(move || {
+			let pinned = cpu.map_or(Ok(()), pin_to_cpu);
+			if let Some(on_start) = on_start {
+				on_start(index);
+			}
+			// Dropped now rather than when the thread exits, so that `build` sees the channel disconnect if another worker's start hook panics
+			let _ = started.send(pinned);
+			drop(started);
+
+			loop {
+				match shared.pop(index) {
+					Some(taskptr) => {
+						if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| taskptr.call_once_box())) {
+							report_panic(&shared.panic, payload);
+						}
+						shared.task_done();
+					},
+					None => if !shared.wait_for_task() {
+						break;
+					}
 				}
-			}))
+			}
+
+			if let Some(on_exit) = on_exit {
+				on_exit(index);
+			}
+		})?;
+
+		Ok(Worker {
+			thread: Some(thread)
+		})
+	}
+}
+
+/// A function called on a worker thread with the index of the worker
+type Hook = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// Configures and builds a `ThreadPool`, for when the defaults of `ThreadPool::new` aren't wanted - For example to limit the number of threads used alongside other work, or to name the threads so profilers can identify them
+///
+/// Workers are indexed from 0, and the index is used in the thread names and passed to the hooks
+#[derive(Default)]
+pub struct ThreadPoolBuilder {
+	num_workers: Option<usize>,
+	thread_name_prefix: Option<String>,
+	stack_size: Option<usize>,
+	cpus: Option<Vec<usize>>,
+	on_start: Option<Hook>,
+	on_exit: Option<Hook>
+}
+
+impl ThreadPoolBuilder {
+	/// Creates a builder with the defaults of `ThreadPool::new` - Unnamed threads with the default stack size, not pinned to any CPU and without hooks
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Sets the number of worker threads - Defaults to the return value of `std::thread::available_parallelism` if `Some`, or if `None`, then 4
+	/// # Panics
+	/// This function will panic if `num_workers` is 0
+	pub fn num_workers(mut self, num_workers: usize) -> Self {
+		assert!(num_workers > 0);
+		self.num_workers = Some(num_workers);
+		self
+	}
+
+	/// Names each worker thread `prefix` followed by its index, such as "aes-worker-0" for the prefix "aes-worker-"
+	pub fn thread_name_prefix(mut self, prefix: impl Into<String>) -> Self {
+		self.thread_name_prefix = Some(prefix.into());
+		self
+	}
+
+	/// Sets the stack size of each worker thread in bytes, as `std::thread::Builder::stack_size` does
+	pub fn stack_size(mut self, stack_size: usize) -> Self {
+		self.stack_size = Some(stack_size);
+		self
+	}
+
+	/// Pins each worker thread to one of `cpus` - Worker `i` is pinned to `cpus[i % cpus.len()]`. Only supported on Linux, elsewhere `ThreadPoolBuilder::build` returns an error
+	/// # Panics
+	/// This function will panic if `cpus` is empty
+	pub fn cpu_affinity(mut self, cpus: impl IntoIterator<Item = usize>) -> Self {
+		let cpus: Vec<usize> = cpus.into_iter().collect();
+		assert!(!cpus.is_empty());
+		self.cpus = Some(cpus);
+		self
+	}
+
+	/// Sets a function called on each worker thread with its index when it starts, before it runs any tasks
+	pub fn on_start<F>(mut self, hook: F) -> Self where F: Fn(usize) + Send + Sync + 'static {
+		self.on_start = Some(Arc::new(hook));
+		self
+	}
+
+	/// Sets a function called on each worker thread with its index when the pool is dropped, after its last task
+	pub fn on_exit<F>(mut self, hook: F) -> Self where F: Fn(usize) + Send + Sync + 'static {
+		self.on_exit = Some(Arc::new(hook));
+		self
+	}
+
+	/// Spawns the worker threads and returns the pool - The start hook has run on every worker by the time this returns
+	///
+	/// Returns an error if a thread can't be spawned or pinned to its CPU, or if the start hook panics, in which case the threads already spawned are shut down
+	pub fn build(&self) -> io::Result<ThreadPool> {
+		let num_workers = self.num_workers.unwrap_or_else(|| match thread::available_parallelism() {
+			Ok(n) => n.into(),
+			Err(_) => 4 // Arbitrarily picked
+		});
+
+		let shared = Arc::new(Shared {
+			queues: (0..num_workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+			queued: AtomicUsize::new(0),
+			sleeping: AtomicUsize::new(0),
+			searching: AtomicUsize::new(0),
+			sleep_condvar: (Condvar::new(), Mutex::new(())),
+			shutdown: AtomicBool::new(false),
+			num_tasks: AtomicUsize::new(0),
+			await_condvar: (Condvar::new(), Mutex::new(())),
+			panic: Mutex::new(None),
+			generation: AtomicUsize::new(0)
+		});
+
+		// Dropping the pool on an error shuts down the workers created so far
+		let mut pool = ThreadPool {
+			workers: Vec::with_capacity(num_workers),
+			shared,
+			next_queue: AtomicUsize::new(0)
+		};
+
+		// Create the workers
+		let (started_sender, started) = mpsc::channel();
+		for index in 0..num_workers {
+			pool.workers.push(Worker::new(index, Arc::clone(&pool.shared), self, started_sender.clone())?);
 		}
+		drop(started_sender);
+
+		// Wait for every worker to start - A worker whose start hook panicked drops its sender without sending
+		for _ in 0..num_workers {
+			started.recv().map_err(|_| io::Error::other("a worker thread's start hook panicked"))??;
+		}
+
+		Ok(pool)
 	}
 }
 
+/// Pins the calling thread to `cpu`
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+	if cpu >= libc::CPU_SETSIZE as usize {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("CPU {} is out of range", cpu)));
+	}
+
+	// SAFETY: `cpu_set_t` is a plain bitset, so all zeroes is a valid empty set, and `cpu` was checked to be in range of it
+	let res = unsafe {
+		let mut set: libc::cpu_set_t = mem::zeroed();
+		libc::CPU_SET(cpu, &mut set);
+		libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set)
+	};
+
+	match res {
+		0 => Ok(()),
+		_ => Err(io::Error::last_os_error())
+	}
+}
+
+/// Pins the calling thread to `cpu` - Not supported on this platform
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(_cpu: usize) -> io::Result<()> {
+	Err(io::Error::new(io::ErrorKind::Unsupported, "pinning threads to CPUs is only supported on Linux"))
+}
+
+/// Handle passed to the closure given to `ThreadPool::scoped`, used to assign tasks - All tasks are complete by the time it is dropped
 pub struct ThreadPoolScope<'p, 's> {
 	pool: &'p mut ThreadPool,
 	scope: PhantomData<std::cell::Cell<&'s ()>>
@@ -142,18 +662,104 @@ impl<'p, 's> ThreadPoolScope<'p, 's> {
 	pub fn assign_task<F>(&self, function: F) where F: FnOnce() + Send + 'p {
 		// Extend the lifetime of the passed-in function to be 'static - Because if it lives as long as 'p, which it must, then it is effectively 'static
 		let function = unsafe { std::mem::transmute::<Task<'p>, Task<'static>>(Box::new(function)) };
-		let message = Message::NewTask(function);
-		self.pool.sender.send(message).unwrap();
-		// Increment the assigned tasks counter
-		self.pool.num_tasks.fetch_add(1, Ordering::SeqCst);
+		// Increment the assigned tasks counter before the task can run and decrement it
+		self.pool.shared.num_tasks.fetch_add(1, Ordering::SeqCst);
+		// Deal the tasks out to the queues in turn
+		let index = self.pool.next_queue.fetch_add(1, Ordering::Relaxed) % self.pool.workers.len();
+		self.pool.shared.push(index, function);
+	}
+
+	/// Assign a task to the ThreadPool like `ThreadPoolScope::assign_task`, and return a handle that can be joined for the task's return value
+	///
+	/// If the task panics, the panic is caught and returned by `TaskHandle::join` instead, or re-raised by `ThreadPool::scoped` if the handle is dropped without being joined
+	pub fn spawn<F, T>(&self, function: F) -> TaskHandle<'p, T> where F: FnOnce() -> T + Send + 'p, T: Send + 'p {
+		let packet = Arc::new(Packet {
+			state: Mutex::new(PacketState { result: None, handle_dropped: false }),
+			condvar: Condvar::new(),
+			shared: Arc::clone(&self.pool.shared),
+			generation: self.pool.shared.generation.load(Ordering::SeqCst)
+		});
+
+		let task_packet = Arc::clone(&packet);
+		self.assign_task(move || {
+			let result = panic::catch_unwind(AssertUnwindSafe(function));
+
+			let mut state = task_packet.state.lock().unwrap();
+			match result {
+				// Nobody can join the task any more, so the panic goes to the scope instead
+				Err(payload) if state.handle_dropped => report_panic(&task_packet.shared.panic, payload),
+				result => state.result = Some(result)
+			}
+			drop(state);
+			task_packet.condvar.notify_all();
+		});
+
+		TaskHandle { packet, scope: PhantomData }
+	}
+
+	/// Calls `f` on `slice` split into runs of whole `chunk_size`-long chunks in parallel, one run per worker thread, with the index of the first chunk of each run - Blocks until every run is processed
+	///
+	/// `f` is called with whole runs rather than single chunks so that it can process them in batches, as the AES kernels do. Only the last chunk of the slice can be shorter than `chunk_size`
+	/// # Panics
+	/// This function will panic if `chunk_size` is 0, or with the payload of the first call of `f` that panicked, after the others are complete
+	pub fn par_chunks_mut<T, F>(&self, slice: &'p mut [T], chunk_size: usize, f: F) where T: Send, F: Fn(usize, &mut [T]) + Send + Sync + 'p {
+		let chunks_per_run = self.chunks_per_run(slice.len(), chunk_size);
+		let f = Arc::new(f);
+
+		let handles: Vec<TaskHandle<()>> = slice.chunks_mut(chunks_per_run * chunk_size).enumerate().map(|(i, run)| {
+			let f = Arc::clone(&f);
+			self.spawn(move || f(i * chunks_per_run, run))
+		}).collect();
+
+		join_all(handles);
+	}
+
+	/// Calls `f` with each index in `range` in parallel, with the range split into one contiguous run of indices per worker thread - Blocks until every index is processed
+	/// # Panics
+	/// This function will panic with the payload of the first call of `f` that panicked, after the others are complete
+	pub fn parallel_for<F>(&self, range: Range<usize>, f: F) where F: Fn(usize) + Send + Sync + 'p {
+		let per_run = self.chunks_per_run(range.len(), 1);
+		let f = Arc::new(f);
+
+		let handles: Vec<TaskHandle<()>> = range.clone().step_by(per_run).map(|start| {
+			let f = Arc::clone(&f);
+			let end = start.saturating_add(per_run).min(range.end);
+			self.spawn(move || (start..end).for_each(|i| f(i)))
+		}).collect();
+
+		join_all(handles);
+	}
+
+	/// Maps runs of whole `chunk_size`-long chunks of `slice` in parallel with `map`, as `ThreadPoolScope::par_chunks_mut` calls `f`, then combines the results in order with `reduce` - Returns `None` if `slice` is empty
+	///
+	/// The results are combined in the order of the runs, so `reduce` needs to be associative but not commutative, such as combining the hashes of consecutive parts of a message
+	/// # Panics
+	/// This function will panic if `chunk_size` is 0, or with the payload of the first call of `map` that panicked, after the others are complete
+	pub fn par_map_reduce<T, R, M, F>(&self, slice: &'p [T], chunk_size: usize, map: M, reduce: F) -> Option<R> where T: Sync, R: Send + 'p, M: Fn(usize, &[T]) -> R + Send + Sync + 'p, F: FnMut(R, R) -> R {
+		let chunks_per_run = self.chunks_per_run(slice.len(), chunk_size);
+		let map = Arc::new(map);
+
+		let handles: Vec<TaskHandle<R>> = slice.chunks(chunks_per_run * chunk_size).enumerate().map(|(i, run)| {
+			let map = Arc::clone(&map);
+			self.spawn(move || map(i * chunks_per_run, run))
+		}).collect();
+
+		join_all(handles).into_iter().reduce(reduce)
+	}
+
+	/// Returns the number of `chunk_size`-long chunks of a `len`-long slice to give each worker thread so that there is one run of chunks per worker
+	fn chunks_per_run(&self, len: usize, chunk_size: usize) -> usize {
+		assert!(chunk_size > 0, "chunk_size must be greater than 0");
+		len.div_ceil(chunk_size).div_ceil(self.pool.workers.len()).max(1)
 	}
 
 	/// Blocks until all currently assigned tasks are complete
 	pub fn await_all(&self) {
 		// Wait until the number of tasks left is 0
-		let _ = self.pool.await_condvar.0.wait_while(self.pool.await_condvar.1.lock().unwrap(), |_| {
+		let await_condvar = &self.pool.shared.await_condvar;
+		let _guard = await_condvar.0.wait_while(await_condvar.1.lock().unwrap(), |_| {
 			// Return whether the number of tasks left is greater than 0; If so then continue to wait
-			self.pool.num_tasks.load(Ordering::SeqCst) > 0
+			self.pool.shared.num_tasks.load(Ordering::SeqCst) > 0
 		}).unwrap();
 	}
 }
@@ -162,6 +768,95 @@ impl<'p, 's> ThreadPoolScope<'p, 's> {
 impl<'p, 's> Drop for ThreadPoolScope<'p, 's> {
 	fn drop(&mut self) {
 		self.await_all();
+		self.pool.shared.generation.fetch_add(1, Ordering::SeqCst);
+	}
+}
+
+/// Where a spawned task stores its result for its `TaskHandle`
+struct Packet<T> {
+	state: Mutex<PacketState<T>>,
+	condvar: Condvar,
+	/// Where the task's panic is reported (`Shared::panic`) if its handle is dropped without being joined
+	shared: Arc<Shared>,
+	/// `Shared::generation` while the task's scope is running
+	generation: usize
+}
+
+struct PacketState<T> {
+	result: Option<thread::Result<T>>,
+	handle_dropped: bool
+}
+
+/// A handle to a task created with `ThreadPoolScope::spawn`, which can be joined for the task's return value
+///
+/// Tasks are complete by the time `ThreadPool::scoped` returns, so a handle returned from the scope can be joined without blocking. Joining a handle from inside another task of the same pool can deadlock if every worker is waiting on a task that has not started
+pub struct TaskHandle<'p, T> {
+	packet: Arc<Packet<T>>,
+	scope: PhantomData<&'p ()>
+}
+
+impl<'p, T> TaskHandle<'p, T> {
+	/// Blocks until the task is complete, then returns its return value, or the payload it panicked with if it panicked
+	pub fn join(self) -> Result<T, PanicPayload> {
+		let mut state = self.packet.condvar.wait_while(self.packet.state.lock().unwrap(), |state| state.result.is_none()).unwrap();
+		state.result.take().unwrap()
+	}
+
+	/// Returns whether the task is complete, without blocking
+	pub fn is_finished(&self) -> bool {
+		self.packet.state.lock().unwrap().result.is_some()
+	}
+}
+
+impl<T> Drop for TaskHandle<'_, T> {
+	fn drop(&mut self) {
+		let mut state = self.packet.state.lock().unwrap();
+		state.handle_dropped = true;
+		if let Some(Err(payload)) = state.result.take() {
+			// Once the scope has ended, a later scope must not re-raise the panic, so it is dropped along with the handle
+			if self.packet.shared.generation.load(Ordering::SeqCst) == self.packet.generation {
+				report_panic(&self.packet.shared.panic, payload);
+			} else {
+				drop_payload(payload);
+			}
+		}
+	}
+}
+
+/// Joins every handle in `handles` and returns their results in order - Once all are joined, panics with the payload of the first task that panicked, if any did
+fn join_all<T>(handles: Vec<TaskHandle<T>>) -> Vec<T> {
+	let mut panic = None;
+	let results = handles.into_iter().filter_map(|handle| match handle.join() {
+		Ok(result) => Some(result),
+		Err(payload) => {
+			panic.get_or_insert(payload);
+			None
+		}
+	}).collect();
+
+	if let Some(payload) = panic {
+		panic::resume_unwind(payload);
+	}
+	results
+}
+
+/// Stores `payload` in `slot` if no panic has been reported yet
+fn report_panic(slot: &Mutex<Option<PanicPayload>>, payload: PanicPayload) {
+	let mut slot_guard = slot.lock().unwrap();
+	if slot_guard.is_none() {
+		*slot_guard = Some(payload);
+		return;
+	}
+	drop(slot_guard);
+
+	// Only the first panic is re-raised
+	drop_payload(payload);
+}
+
+/// Drops a panic payload - Dropping it runs its destructor, which could itself panic and must not take down the worker
+fn drop_payload(payload: PanicPayload) {
+	if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| drop(payload))) {
+		mem::forget(payload);
 	}
 }
 
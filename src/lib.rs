@@ -1 +1,28 @@
-pub mod cpu;
\ No newline at end of file
+//! AES encryption on the CPU, using x86/x86_64 AES-NI intrinsics if available and a portable implementation otherwise
+//!
+//! - AES/CTR: `aes_encrypt`, `aes_decrypt` and `aes_encrypt_decrypt`, which can be run in parallel on a `ThreadPool` with `aes_encrypt_decrypt_par`, or by splitting the data into `AesBlock`s with `AesBlock::decompose`
+//! - AES/CBC: The `cbc` module
+//! - AES-GCM: The `gcm` module
+//! - `std::io` adapters that encrypt or decrypt with AES/CTR on the fly: The `stream` module
+//!
+//! The AES/CTR functions take keys and data in this crate's original byte-reversed layout (`ByteOrder::Legacy`) for backward compatibility. The `_with_order` variants and `AesBlock::decompose_with_order` take a `ByteOrder`, where `ByteOrder::Standard` is the FIPS-197/NIST layout used by other AES implementations
+//!
+//! By default the whole 128-bit counter block is the CTR counter. The `_with_layout` methods of `ExpandedKey` take a `CounterLayout` instead, such as a 96-bit nonce followed by a 32-bit counter. Whatever the layout, the functions return `AesError::CounterOverflow` (or panic, for the ones without `try_`) rather than let the counter wrap and reuse keystream. `aes_encrypt_decrypt_at` encrypts or decrypts any byte range of a message, starting from a byte offset, and `AesCtr` encrypts or decrypts a message that arrives in pieces
+//!
+//! Each of these functions expands the key for every call. To encrypt many messages under the same key, create an `ExpandedKey` once, which has methods for every mode and can be shared between threads
+//!
+//! The functions that panic on invalid input, such as a key of the wrong length, have `try_` variants that return an `AesError` instead, which also covers failure to generate an IV, counter overflow and failed authentication
+//!
+//! AES-128, AES-192 or AES-256 is picked by the length of the key. The fastest implementation the CPU supports is picked at runtime - `Backend::detect` returns it, `Backend::set_default` picks another one for the whole process, and `ExpandedKey::with_backend` uses a specific one for a single key, for testing or reproducible benchmarks
+//!
+//! The portable implementation used without AES-NI looks up tables indexed by secret data, which can leak the key through cache timing. Enable the `constant-time` feature to use a bitsliced implementation instead (`Backend::Bitsliced`), which is slower but constant-time
+//! Enable the `ttable` feature to use a T-table implementation instead (`Backend::Ttable`), which is several times faster but not constant-time either. If both are enabled, `constant-time` is used. All three portable implementations are always built, so they can also be picked with `Backend::set_default` or `ExpandedKey::with_backend`
+//!
+//! Copies of the key and the round keys made by this crate are zeroed when they are dropped, with writes that the compiler can't optimise away. The key passed in is the caller's to wipe
+//!
+//! `ThreadPool` is a thread pool whose tasks can borrow local variables, which is used to process blocks in parallel - `ThreadPoolScope::spawn` returns a `TaskHandle` that can be joined for the result of a task, and `ThreadPoolScope::par_chunks_mut`, `ThreadPoolScope::parallel_for` and `ThreadPoolScope::par_map_reduce` split data into one run per worker. `ThreadPoolBuilder` configures the number, names, stack size and CPU affinity of the worker threads
+
+mod cpu;
+
+pub use cpu::aes::{aes_decrypt, aes_encrypt, aes_encrypt_decrypt, aes_encrypt_decrypt_at, aes_encrypt_decrypt_par, aes_encrypt_decrypt_par_with_order, aes_encrypt_decrypt_with_order, cbc, gcm, stream, try_aes_decrypt, try_aes_encrypt, try_aes_encrypt_decrypt, try_aes_encrypt_decrypt_par, try_aes_encrypt_decrypt_par_with_order, try_aes_encrypt_decrypt_with_order, AesBlock, AesCtr, AesError, Backend, ByteOrder, CounterLayout, ExpandedKey};
+pub use cpu::scoped_thread_pool::{PanicPayload, TaskHandle, ThreadPool, ThreadPoolBuilder, ThreadPoolScope};
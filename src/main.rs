@@ -1,3 +1,495 @@
-fn main() {
-	todo!();
+//! Command-line tool to encrypt and decrypt files (or stdin/stdout) with AES/CTR, using a thread pool to encrypt in parallel
+//!
+//! The IV (and the salt, if the key is derived from a passphrase) is stored in a small header at the start of the encrypted output, so only the key or passphrase is needed for decryption
+
+use std::{env, fs, io::{self, Read, Write}, ops::Deref, process::ExitCode, ptr, sync::atomic::{self, Ordering}};
+
+use aes_par::{aes_encrypt_decrypt_par_with_order, ByteOrder, ThreadPool};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+const USAGE: &str = "\
+Usage: aes_par <encrypt|decrypt> <KEY OPTION> [OPTIONS]
+
+Encrypts or decrypts with AES/CTR. Note that AES/CTR provides confidentiality only - modifications to the encrypted data are not detected
+
+Key options (exactly one is required):
+    -k, --key <HEX>              128, 192 or 256-bit key as hex
+    -f, --key-file <PATH>        File containing a key as hex, or a raw 16, 24 or 32-byte key
+    -p, --passphrase-file <PATH> Derive the key from the first line of PATH using PBKDF2-HMAC-SHA256
+    -e, --passphrase-env <VAR>   Derive the key from the environment variable VAR using PBKDF2-HMAC-SHA256
+
+Options:
+    -i, --input <PATH>           Read input from PATH instead of stdin
+    -o, --output <PATH>          Write output to PATH instead of stdout
+    -t, --threads <N>            Number of worker threads [default: available parallelism]
+    -s, --key-size <BITS>        Size of a key derived from a passphrase: 128, 192 or 256 [default: 256]
+    -h, --help                   Print this message";
+
+/// Magic bytes at the start of every encrypted file
+const MAGIC: &[u8; 4] = b"AESP";
+/// Header format version - Version 2 files are encrypted with `ByteOrder::Standard`, so they can be decrypted by other AES/CTR implementations given the key and the IV
+const VERSION: u8 = 2;
+/// The header format version of files encrypted with `ByteOrder::Legacy`, which can still be decrypted
+const VERSION_LEGACY: u8 = 1;
+/// Header KDF identifier for a key that was provided directly
+const KDF_NONE: u8 = 0;
+/// Header KDF identifier for a key derived with PBKDF2-HMAC-SHA256
+const KDF_PBKDF2_SHA256: u8 = 1;
+/// PBKDF2 iteration count used for new files - Stored in the header, so it can be raised without breaking old files
+const PBKDF2_ITERATIONS: u32 = 600_000;
+/// The highest PBKDF2 iteration count accepted from a header - The header is untrusted, and a huge count would keep decryption busy for hours
+const MAX_PBKDF2_ITERATIONS: u32 = 4 * PBKDF2_ITERATIONS;
+
+#[cfg(test)]
+#[test]
+fn test_header() {
+	let headers = [
+		Header { order: ByteOrder::Standard, kdf: None, iv: 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff },
+		Header { order: ByteOrder::Standard, kdf: Some(KdfParams { key_bits: 192, iterations: 1000, salt: [7; 16] }), iv: 1 },
+		Header { order: ByteOrder::Legacy, kdf: None, iv: 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff }
+	];
+
+	for header in headers {
+		let mut bytes = header.to_bytes();
+		bytes.extend_from_slice(b"ciphertext");
+
+		let (parsed, len) = Header::parse(&bytes).unwrap();
+
+		assert_eq!(parsed, header);
+		assert_eq!(&bytes[len..], b"ciphertext");
+	}
+
+	assert!(Header::parse(b"AESP").is_err());
+	assert!(Header::parse(b"not an encrypted file").is_err());
+
+	// The IV is stored as the standard byte string, which is its big-endian form
+	let bytes = Header { order: ByteOrder::Standard, kdf: None, iv: 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff }.to_bytes();
+	assert_eq!(&bytes[6..], &0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeffu128.to_be_bytes());
+
+	let header = Header { order: ByteOrder::Standard, kdf: Some(KdfParams { key_bits: 256, iterations: MAX_PBKDF2_ITERATIONS + 1, salt: [7; 16] }), iv: 1 };
+	assert!(Header::parse(&header.to_bytes()).is_err(), "[ERROR]: A header with too many PBKDF2 iterations was accepted");
+}
+
+#[cfg(test)]
+#[test]
+fn test_parse_args() {
+	let args = |args: &[&str]| parse_args(args.iter().map(|a| a.to_string()).collect());
+
+	assert!(args(&["-h"]).unwrap().is_none());
+	assert!(args(&["encrypt", "-k", "00", "--help"]).unwrap().is_none());
+
+	// An option's value is never taken as the help flag
+	let parsed = args(&["encrypt", "-p", "-h"]).unwrap().unwrap();
+	assert_eq!(parsed.key_source, KeySource::PassphraseFile("-h".to_string()));
+
+	// The key size only applies to a key derived from a passphrase
+	assert_eq!(args(&["encrypt", "-e", "PASS", "-s", "128"]).unwrap().unwrap().key_bits, 128);
+	assert!(args(&["encrypt", "-k", "00", "-s", "128"]).is_err(), "[ERROR]: A key size was accepted alongside a key");
+	assert!(args(&["encrypt", "-s", "128", "-f", "key"]).is_err(), "[ERROR]: A key size was accepted alongside a key file");
+}
+
+#[cfg(test)]
+#[test]
+fn test_key_file_contents() {
+	let read = |contents: &[u8]| key_from_file_contents(contents).map(|key| key.to_vec());
+	let key = vec![0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6, 0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c];
+
+	// Hex with or without a trailing newline, including 32 hex characters which are also 32 bytes long
+	assert_eq!(read(b"2b7e151628aed2a6abf7158809cf4f3c"), Some(key.clone()), "[ERROR]: A hex key file without a newline was not read as hex");
+	assert_eq!(read(b"2b7e151628aed2a6abf7158809cf4f3c\n"), Some(key.clone()));
+
+	// Raw keys
+	let raw = vec![0xffu8; 16];
+	assert_eq!(read(&raw), Some(raw));
+	assert_eq!(read(&key), Some(key));
+
+	assert_eq!(read(&[0xff; 20]), None);
+	assert_eq!(read(b"2b7"), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_encrypt_decrypt_file() {
+	let plaintext: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+	let mut pool = ThreadPool::with_num_workers(3);
+
+	let passphrase_file = env::temp_dir().join(format!("aes_par_test_passphrase_{}", std::process::id()));
+	fs::write(&passphrase_file, "correct horse battery staple\n").unwrap();
+
+	for key_source in [KeySource::Hex("2b7e151628aed2a6abf7158809cf4f3c".to_string()), KeySource::PassphraseFile(passphrase_file.to_str().unwrap().to_string())] {
+		let args = Args { mode: Mode::Encrypt, key_source, key_bits: 128, input: None, output: None, threads: None };
+
+		// Use a low iteration count to keep the test fast
+		let encrypted = encrypt(plaintext.clone(), &args, 10, &mut pool).unwrap();
+		assert_ne!(&encrypted[(encrypted.len() - plaintext.len())..], &plaintext[..]);
+
+		let decrypted = decrypt(encrypted, &args, &mut pool).unwrap();
+		assert_eq!(decrypted, plaintext);
+	}
+
+	fs::remove_file(passphrase_file).unwrap();
+
+	// The ciphertext is standard AES/CTR, so a file made from the NIST SP 800-38A F.5.1 test vector decrypts
+	let args = Args { mode: Mode::Decrypt, key_source: KeySource::Hex("2b7e151628aed2a6abf7158809cf4f3c".to_string()), key_bits: 128, input: None, output: None, threads: None };
+	let mut file = Header { order: ByteOrder::Standard, kdf: None, iv: 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeff }.to_bytes();
+	file.extend_from_slice(&parse_hex("874d6191b620e3261bef6864990db6ce").unwrap());
+	assert_eq!(decrypt(file, &args, &mut pool).unwrap(), parse_hex("6bc1bee22e409f96e93d7e117393172a").unwrap().to_vec(), "[ERROR]: The tool's output is not standard AES/CTR");
+}
+
+/// Whether to encrypt or decrypt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+	Encrypt,
+	Decrypt
+}
+
+/// Where the key comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeySource {
+	Hex(String),
+	File(String),
+	/// The path of a file containing the passphrase
+	PassphraseFile(String),
+	/// The name of an environment variable containing the passphrase
+	PassphraseEnv(String)
+}
+
+impl KeySource {
+	fn is_passphrase(&self) -> bool {
+		matches!(self, KeySource::PassphraseFile(_) | KeySource::PassphraseEnv(_))
+	}
+}
+
+/// Bytes of a key or passphrase, overwritten with zeroes when dropped so they don't linger in freed memory
+struct Secret(Vec<u8>);
+
+impl Deref for Secret {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl Drop for Secret {
+	fn drop(&mut self) {
+		for b in self.0.iter_mut() {
+			// Volatile writes, so that the compiler can't remove them as dead stores
+			unsafe { ptr::write_volatile(b, 0) };
+		}
+		atomic::compiler_fence(Ordering::SeqCst);
+	}
+}
+
+/// Parsed command-line arguments
+#[derive(Debug)]
+struct Args {
+	mode: Mode,
+	key_source: KeySource,
+	key_bits: usize,
+	input: Option<String>,
+	output: Option<String>,
+	threads: Option<usize>
+}
+
+/// Parameters needed to re-derive a key from a passphrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KdfParams {
+	key_bits: usize,
+	iterations: u32,
+	salt: [u8; 16]
+}
+
+/// The header at the start of an encrypted file
+///
+/// Layout: `MAGIC` (4 bytes), `VERSION` (1 byte), KDF identifier (1 byte), then if the KDF is PBKDF2 the key size in bits (2 bytes, little-endian), iteration count (4 bytes, little-endian) and salt (16 bytes), then the IV (16 bytes, big-endian, or little-endian in `VERSION_LEGACY` files)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+	/// The byte order the data was encrypted with, which is given by the header version
+	order: ByteOrder,
+	kdf: Option<KdfParams>,
+	iv: u128
+}
+
+impl Header {
+	fn to_bytes(self) -> Vec<u8> {
+		let mut bytes = MAGIC.to_vec();
+		bytes.push(match self.order {
+			ByteOrder::Standard => VERSION,
+			ByteOrder::Legacy => VERSION_LEGACY
+		});
+
+		match &self.kdf {
+			None => bytes.push(KDF_NONE),
+			Some(params) => {
+				bytes.push(KDF_PBKDF2_SHA256);
+				bytes.extend_from_slice(&(params.key_bits as u16).to_le_bytes());
+				bytes.extend_from_slice(&params.iterations.to_le_bytes());
+				bytes.extend_from_slice(&params.salt);
+			}
+		}
+
+		bytes.extend_from_slice(&match self.order {
+			ByteOrder::Standard => self.iv.to_be_bytes(),
+			ByteOrder::Legacy => self.iv.to_le_bytes()
+		});
+
+		bytes
+	}
+
+	/// Parses the header at the start of `bytes`, returning it and its length in bytes
+	fn parse(bytes: &[u8]) -> Result<(Header, usize), String> {
+		let too_short = || "input is too short to be an encrypted file".to_string();
+
+		if bytes.len() < 6 || &bytes[..4] != MAGIC {
+			return Err("input is not an encrypted file (missing header)".to_string());
+		}
+		let order = match bytes[4] {
+			VERSION => ByteOrder::Standard,
+			VERSION_LEGACY => ByteOrder::Legacy,
+			version => return Err(format!("unsupported header version {}", version))
+		};
+
+		let mut pos = 6;
+		let kdf = match bytes[5] {
+			KDF_NONE => None,
+			KDF_PBKDF2_SHA256 => {
+				let params = bytes.get(pos..(pos + 22)).ok_or_else(too_short)?;
+				pos += 22;
+				let params = KdfParams {
+					key_bits: u16::from_le_bytes(params[..2].try_into().unwrap()) as usize,
+					iterations: u32::from_le_bytes(params[2..6].try_into().unwrap()),
+					salt: params[6..].try_into().unwrap()
+				};
+				if !matches!(params.key_bits, 128 | 192 | 256) || params.iterations == 0 || params.iterations > MAX_PBKDF2_ITERATIONS {
+					return Err("header contains invalid key derivation parameters".to_string());
+				}
+				Some(params)
+			},
+			kdf => return Err(format!("unsupported key derivation function {}", kdf))
+		};
+
+		let iv: [u8; 16] = bytes.get(pos..(pos + 16)).ok_or_else(too_short)?.try_into().unwrap();
+		let iv = match order {
+			ByteOrder::Standard => u128::from_be_bytes(iv),
+			ByteOrder::Legacy => u128::from_le_bytes(iv)
+		};
+		pos += 16;
+
+		Ok((Header { order, kdf, iv }, pos))
+	}
+}
+
+fn main() -> ExitCode {
+	let args: Vec<String> = env::args().skip(1).collect();
+
+	if args.is_empty() {
+		println!("{}", USAGE);
+		return ExitCode::SUCCESS;
+	}
+
+	match run(args) {
+		Ok(()) => ExitCode::SUCCESS,
+		Err(e) => {
+			eprintln!("aes_par: {}", e);
+			ExitCode::FAILURE
+		}
+	}
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+	let Some(args) = parse_args(args)? else {
+		println!("{}", USAGE);
+		return Ok(());
+	};
+
+	let mut input = Vec::new();
+	match &args.input {
+		Some(path) => input = fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?,
+		None => {
+			io::stdin().read_to_end(&mut input).map_err(|e| format!("could not read stdin: {}", e))?;
+		}
+	}
+
+	let mut pool = match args.threads {
+		Some(n) => ThreadPool::with_num_workers(n),
+		None => ThreadPool::new()
+	};
+
+	let output = match args.mode {
+		Mode::Encrypt => encrypt(input, &args, PBKDF2_ITERATIONS, &mut pool)?,
+		Mode::Decrypt => decrypt(input, &args, &mut pool)?
+	};
+
+	match &args.output {
+		Some(path) => fs::write(path, output).map_err(|e| format!("could not write {}: {}", path, e)),
+		None => io::stdout().write_all(&output).map_err(|e| format!("could not write stdout: {}", e))
+	}
+}
+
+/// Parses the command-line arguments - Returns `None` if help was asked for where a command or option name was expected
+fn parse_args(args: Vec<String>) -> Result<Option<Args>, String> {
+	let mut args = args.into_iter();
+
+	let mode = match args.next().as_deref() {
+		Some("-h" | "--help") => return Ok(None),
+		Some("encrypt") => Mode::Encrypt,
+		Some("decrypt") => Mode::Decrypt,
+		Some(other) => return Err(format!("unknown command '{}', expected 'encrypt' or 'decrypt'", other)),
+		None => return Err("missing command, expected 'encrypt' or 'decrypt'".to_string())
+	};
+
+	let mut key_source = None;
+	let mut key_bits = None;
+	let mut input = None;
+	let mut output = None;
+	let mut threads = None;
+
+	while let Some(arg) = args.next() {
+		let mut value = || args.next().ok_or_else(|| format!("missing value for {}", arg));
+
+		match arg.as_str() {
+			"-k" | "--key" | "-f" | "--key-file" | "-p" | "--passphrase-file" | "-e" | "--passphrase-env" => {
+				if key_source.is_some() {
+					return Err("only one of --key, --key-file, --passphrase-file and --passphrase-env can be given".to_string());
+				}
+				key_source = Some(match arg.as_str() {
+					"-k" | "--key" => KeySource::Hex(value()?),
+					"-f" | "--key-file" => KeySource::File(value()?),
+					"-p" | "--passphrase-file" => KeySource::PassphraseFile(value()?),
+					_ => KeySource::PassphraseEnv(value()?)
+				});
+			},
+			"-s" | "--key-size" => {
+				key_bits = match value()?.as_str() {
+					"128" => Some(128),
+					"192" => Some(192),
+					"256" => Some(256),
+					other => return Err(format!("invalid key size '{}', expected 128, 192 or 256", other))
+				};
+			},
+			"-i" | "--input" => input = Some(value()?),
+			"-o" | "--output" => output = Some(value()?),
+			"-t" | "--threads" => {
+				let value = value()?;
+				threads = match value.parse::<usize>() {
+					Ok(n) if n > 0 => Some(n),
+					_ => return Err(format!("invalid thread count '{}'", value))
+				};
+			},
+			"-h" | "--help" => return Ok(None),
+			_ => return Err(format!("unknown option '{}'", arg))
+		}
+	}
+
+	let key_source = key_source.ok_or("a key is required: use --key, --key-file, --passphrase-file or --passphrase-env")?;
+
+	// The size of a given key is the size of the key, so a different one is a mistake rather than something to ignore
+	if key_bits.is_some() && !key_source.is_passphrase() {
+		return Err("--key-size only applies to a key derived from a passphrase".to_string());
+	}
+	let key_bits = key_bits.unwrap_or(256);
+
+	Ok(Some(Args { mode, key_source, key_bits, input, output, threads }))
+}
+
+/// Encrypts `data`, returning the header followed by the ciphertext
+fn encrypt(mut data: Vec<u8>, args: &Args, iterations: u32, pool: &mut ThreadPool) -> Result<Vec<u8>, String> {
+	let (key, kdf) = match &args.key_source {
+		source if source.is_passphrase() => {
+			let mut salt = [0u8; 16];
+			ChaCha20Rng::from_entropy().fill_bytes(&mut salt);
+			let params = KdfParams { key_bits: args.key_bits, iterations, salt };
+			(derive_key(&load_passphrase(source)?, &params), Some(params))
+		},
+		source => (load_key(source)?, None)
+	};
+
+	let iv = aes_encrypt_decrypt_par_with_order(&mut data, &key, None, ByteOrder::Standard, pool);
+
+	let mut output = Header { order: ByteOrder::Standard, kdf, iv }.to_bytes();
+	output.append(&mut data);
+
+	Ok(output)
+}
+
+/// Decrypts the header-prefixed ciphertext `data`, returning the plaintext
+fn decrypt(data: Vec<u8>, args: &Args, pool: &mut ThreadPool) -> Result<Vec<u8>, String> {
+	let (header, header_len) = Header::parse(&data)?;
+
+	let key = match (&args.key_source, &header.kdf) {
+		(source, Some(params)) if source.is_passphrase() => derive_key(&load_passphrase(source)?, params),
+		(source, None) if source.is_passphrase() => return Err("input was encrypted with a key, not a passphrase: use --key or --key-file".to_string()),
+		(_, Some(_)) => return Err("input was encrypted with a passphrase: use --passphrase-file or --passphrase-env".to_string()),
+		(source, None) => load_key(source)?
+	};
+
+	let mut data = data[header_len..].to_vec();
+
+	aes_encrypt_decrypt_par_with_order(&mut data, &key, Some(header.iv), header.order, pool);
+
+	Ok(data)
+}
+
+/// Reads a key given as hex or in a key file
+fn load_key(source: &KeySource) -> Result<Secret, String> {
+	let key = match source {
+		KeySource::Hex(hex) => parse_hex(hex)?,
+		KeySource::File(path) => {
+			let contents = Secret(fs::read(path).map_err(|e| format!("could not read key file {}: {}", path, e))?);
+			key_from_file_contents(&contents).ok_or_else(|| format!("key file {} is neither hex nor a raw 16, 24 or 32-byte key", path))?
+		},
+		_ => unreachable!("passphrases are handled by load_passphrase")
+	};
+
+	if !matches!(key.len(), 16 | 24 | 32) {
+		return Err(format!("key is {} bytes, but must be 16, 24 or 32 bytes (128, 192 or 256-bit)", key.len()));
+	}
+
+	Ok(key)
+}
+
+/// Interprets the contents of a key file - A file that is entirely hex (ignoring surrounding whitespace) is read as hex, whatever its length, so a 32-character hex key without a trailing newline isn't mistaken for a raw 32-byte key - Anything else must be a raw key
+fn key_from_file_contents(contents: &[u8]) -> Option<Secret> {
+	let hex = contents.trim_ascii();
+	if !hex.is_empty() && hex.iter().all(|b| b.is_ascii_hexdigit()) {
+		return parse_hex(std::str::from_utf8(hex).unwrap()).ok();
+	}
+
+	matches!(contents.len(), 16 | 24 | 32).then(|| Secret(contents.to_vec()))
+}
+
+/// Reads a passphrase from the first line of a file or from an environment variable - Passphrases aren't taken as arguments, as those can be seen by other users of the system
+fn load_passphrase(source: &KeySource) -> Result<Secret, String> {
+	let passphrase = match source {
+		KeySource::PassphraseFile(path) => {
+			let contents = Secret(fs::read(path).map_err(|e| format!("could not read passphrase file {}: {}", path, e))?);
+			let line = contents.split(|&b| b == b'\n').next().unwrap();
+			Secret(line.strip_suffix(b"\r").unwrap_or(line).to_vec())
+		},
+		KeySource::PassphraseEnv(var) => Secret(env::var(var).map_err(|e| format!("could not read passphrase from {}: {}", var, e))?.into_bytes()),
+		_ => unreachable!("keys are handled by load_key")
+	};
+
+	if passphrase.is_empty() {
+		return Err("passphrase is empty".to_string());
+	}
+
+	Ok(passphrase)
+}
+
+/// Derives a key from `passphrase` using PBKDF2-HMAC-SHA256
+fn derive_key(passphrase: &[u8], params: &KdfParams) -> Secret {
+	let mut key = Secret(vec![0u8; params.key_bits / 8]);
+	pbkdf2::pbkdf2_hmac::<sha2::Sha256>(passphrase, &params.salt, params.iterations, &mut key.0);
+	key
+}
+
+fn parse_hex(hex: &str) -> Result<Secret, String> {
+	if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+		return Err("key is not valid hex".to_string());
+	}
+
+	Ok(Secret((0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..(i + 2)], 16).unwrap()).collect()))
 }
@@ -0,0 +1,86 @@
+//! Checks that no key material is left in freed memory - This is its own test binary, as it replaces the global allocator, which would otherwise slow down the other tests and see their allocations
+
+use std::{alloc::{GlobalAlloc, Layout, System}, cell::Cell};
+
+use aes_par::{cbc::AesCbcBlock, gcm, AesBlock, AesCtr, ByteOrder, CounterLayout, ExpandedKey};
+
+/// An allocator that looks for a pattern in every block freed by the current thread
+struct CheckingAllocator;
+
+thread_local! {
+	/// The pattern to look for, and the number of freed blocks it has been found in
+	static WATCH: Cell<Option<([u8; 16], usize)>> = const { Cell::new(None) };
+}
+
+unsafe impl GlobalAlloc for CheckingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		System.alloc(layout)
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		// try_with, as the thread local may already be gone while a thread exits
+		let _ = WATCH.try_with(|watch| {
+			if let Some((pattern, found)) = watch.get() {
+				let block = std::slice::from_raw_parts(ptr, layout.size());
+				if block.windows(16).any(|w| w == pattern) {
+					watch.set(Some((pattern, found + 1)));
+				}
+			}
+		});
+
+		System.dealloc(ptr, layout)
+	}
+}
+
+#[global_allocator]
+static ALLOCATOR: CheckingAllocator = CheckingAllocator;
+
+/// Runs `f` and returns the number of blocks freed by the current thread meanwhile that still contained `pattern`
+fn count_freed_with(pattern: [u8; 16], f: impl FnOnce()) -> usize {
+	WATCH.with(|watch| watch.set(Some((pattern, 0))));
+	f();
+	WATCH.with(|watch| watch.take()).unwrap().1
+}
+
+#[test]
+fn test_zeroize_on_drop() {
+	const KEY: [u8; 16] = 0x8f3a7c21d4e5b6a7980112233445566fu128.to_be_bytes();
+
+	let mut reversed = KEY;
+	reversed.reverse();
+
+	// Sanity check that the allocator finds key material left in memory
+	let found = count_freed_with(KEY, || drop(KEY.to_vec()));
+	assert_eq!(found, 1, "[ERROR]: The checking allocator did not find a freed key");
+
+	// The key appears in memory as is and byte-reversed, as the first round key is the key itself, stored as a u128
+	for pattern in [KEY, reversed] {
+		for order in [ByteOrder::Legacy, ByteOrder::Standard] {
+			let found = count_freed_with(pattern, || {
+				let key = ExpandedKey::new(&KEY, order);
+
+				let mut data = vec![0u8; 100];
+				key.encrypt_decrypt(&mut data, Some(0)).unwrap();
+				key.cbc_decrypt_nopad(&mut data[..96], 0).unwrap();
+
+				let mut blocks = key.decompose(&mut data, Some(0)).unwrap();
+				AesBlock::encrypt_batch(&mut blocks);
+				let cbc_blocks = key.cbc_decompose(&mut data[..96], 0).unwrap();
+				drop(cbc_blocks);
+
+				let mut ctr = key.ctr(0, CounterLayout::FULL);
+				drop(key);
+				ctr.apply_keystream(&mut data).unwrap();
+				drop(ctr);
+
+				// The free functions expand the key internally
+				aes_par::aes_encrypt_decrypt_with_order(&mut data, &KEY, Some(0), order);
+				AesCtr::new(&KEY, 0, CounterLayout::FULL, order).apply_keystream(&mut data).unwrap();
+				AesCbcBlock::decompose(&mut data[..96], &KEY, 0).iter_mut().for_each(|b| b.decrypt());
+				gcm::aes_gcm_encrypt(&mut data, &KEY, &[0; 12], &[]);
+			});
+
+			assert_eq!(found, 0, "[ERROR]: Key material was left in freed memory");
+		}
+	}
+}